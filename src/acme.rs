@@ -0,0 +1,912 @@
+use crate::cert_manager::{CertResolver, certified_key_from_pem, http_client, write_private_file};
+use crate::config::{Acme, AcmeChallenge};
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rcgen::{
+    Certificate, CertificateParams, CustomExtension, DistinguishedName, DnType, KeyPair,
+    PKCS_ECDSA_P256_SHA256,
+};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use ring::digest::{SHA256, digest};
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _};
+use rustls::sign::CertifiedKey;
+use rustls_pemfile::{certs, private_key};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::extensions::GeneralName;
+
+/// Number of times an authorization or order is polled before giving up
+const POLL_ATTEMPTS: u32 = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Delay before retrying after a failed issuance
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+/// How long a `dns_hook` invocation may run before it is killed
+const DNS_HOOK_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+}
+
+struct AcmeResponse {
+    location: Option<String>,
+    body: String,
+}
+
+impl AcmeResponse {
+    fn parse<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_str(&self.body)
+            .map_err(|e| anyhow!("Invalid ACME response {}: {}", self.body, e))
+    }
+}
+
+/// Validity and names of an issued certificate
+struct CertificateInfo {
+    not_before: SystemTime,
+    not_after: SystemTime,
+    dns_names: Vec<String>,
+}
+
+impl CertificateInfo {
+    /// Renew `renew_before` ahead of expiry, but never earlier than half-way through the lifetime
+    fn renewal_time(&self, renew_before: Duration) -> SystemTime {
+        let lifetime = self
+            .not_after
+            .duration_since(self.not_before)
+            .unwrap_or_default();
+        self.not_after - renew_before.min(lifetime / 2)
+    }
+
+    fn covers(&self, domains: &[String]) -> bool {
+        domains.iter().all(|domain| self.dns_names.contains(domain))
+    }
+}
+
+/// Client for a single ACME account, speaking the RFC 8555 JWS protocol
+struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    account_url: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(directory_url: &str, account_key_pkcs8: &[u8]) -> Result<Self> {
+        let http = http_client(Vec::new())?;
+
+        let response = http
+            .get(directory_url)
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to fetch ACME directory {}: {}", directory_url, e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "HTTP error {} when fetching ACME directory {}",
+                response.status(),
+                directory_url
+            ));
+        }
+        let directory: Directory = serde_json::from_str(&response.text().await?)
+            .map_err(|e| anyhow!("Invalid ACME directory {}: {}", directory_url, e))?;
+
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, account_key_pkcs8, &rng)
+                .map_err(|e| anyhow!("Invalid ACME account key: {}", e))?;
+
+        Ok(Self {
+            http,
+            directory,
+            key,
+            rng,
+            account_url: None,
+            nonce: None,
+        })
+    }
+
+    fn jwk(&self) -> Value {
+        // Uncompressed point: 0x04 || x || y
+        let public_key = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&public_key[1..33]),
+            "y": b64(&public_key[33..65]),
+        })
+    }
+
+    /// RFC 7638 thumbprint over the required JWK members in lexicographic order
+    fn thumbprint(&self) -> String {
+        let jwk = self.jwk();
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+        b64(digest(&SHA256, canonical.as_bytes()).as_ref())
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint())
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to fetch ACME nonce: {}", e))?;
+        replay_nonce(&response).ok_or_else(|| anyhow!("ACME server did not return a nonce"))
+    }
+
+    /// Send a signed request; a `None` payload makes it a POST-as-GET
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse> {
+        let mut retried = false;
+        loop {
+            let mut protected = json!({ "alg": "ES256", "nonce": self.nonce().await?, "url": url });
+            match &self.account_url {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+
+            let protected = b64(protected.to_string().as_bytes());
+            let payload = payload
+                .map(|p| b64(p.to_string().as_bytes()))
+                .unwrap_or_default();
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+                .map_err(|_| anyhow!("Failed to sign ACME request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": b64(signature.as_ref()),
+            });
+
+            let response = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(|e| anyhow!("ACME request to {} failed: {}", url, e))?;
+
+            self.nonce = replay_nonce(&response);
+            let status = response.status();
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let body = response.text().await?;
+
+            if status.is_success() {
+                return Ok(AcmeResponse { location, body });
+            }
+            // Nonces can go stale; the error response carries a fresh one to retry with
+            if !retried && body.contains("urn:ietf:params:acme:error:badNonce") {
+                retried = true;
+                continue;
+            }
+            return Err(anyhow!(
+                "ACME request to {} failed with {}: {}",
+                url,
+                status,
+                body
+            ));
+        }
+    }
+
+    async fn register_account(&mut self, contact: &[String]) -> Result<()> {
+        let contact: Vec<String> = contact
+            .iter()
+            .map(|c| {
+                if c.contains(':') {
+                    c.clone()
+                } else {
+                    format!("mailto:{c}")
+                }
+            })
+            .collect();
+
+        let url = self.directory.new_account.clone();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+        let response = self.post(&url, Some(&payload)).await?;
+        let account_url = response
+            .location
+            .ok_or_else(|| anyhow!("ACME server did not return an account URL"))?;
+
+        tracing::debug!("Using ACME account {}", account_url);
+        self.account_url = Some(account_url);
+        Ok(())
+    }
+
+    /// Run a full order and return the PEM certificate chain and private key
+    async fn issue(&mut self, config: &Acme, resolver: &CertResolver) -> Result<(String, String)> {
+        let identifiers: Vec<Value> = config
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+
+        let url = self.directory.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = response
+            .location
+            .clone()
+            .ok_or_else(|| anyhow!("ACME server did not return an order URL"))?;
+        let order: Order = response.parse()?;
+
+        for authorization_url in &order.authorizations {
+            self.authorize(authorization_url, config, resolver).await?;
+        }
+
+        // Servers only accept the CSR once every authorization is valid (RFC 8555 section 7.4)
+        self.poll_order(&order_url, "ready").await?;
+
+        // Finalize with a CSR for a freshly generated key
+        let mut params = CertificateParams::new(config.domains.clone());
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, config.domains[0].as_str());
+        let csr_source = Certificate::from_params(params)?;
+        let csr = csr_source.serialize_request_der()?;
+        self.post(&order.finalize, Some(&json!({ "csr": b64(&csr) })))
+            .await?;
+
+        let order = self.poll_order(&order_url, "valid").await?;
+        let certificate_url = order
+            .certificate
+            .ok_or_else(|| anyhow!("ACME order {} has no certificate", order_url))?;
+        let cert_pem = self.post(&certificate_url, None).await?.body;
+
+        Ok((cert_pem, csr_source.serialize_private_key_pem()))
+    }
+
+    async fn authorize(
+        &mut self,
+        authorization_url: &str,
+        config: &Acme,
+        resolver: &CertResolver,
+    ) -> Result<()> {
+        let authorization: Authorization = self.post(authorization_url, None).await?.parse()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let domain = authorization.identifier.value.as_str();
+        let kind = match config.challenge {
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+            AcmeChallenge::Dns01 => "dns-01",
+        };
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.kind == kind)
+            .ok_or_else(|| anyhow!("ACME server offered no {} challenge for {}", kind, domain))?;
+        let key_authorization = self.key_authorization(&challenge.token);
+        let record_name = format!("_acme-challenge.{domain}");
+        let record_value = b64(digest(&SHA256, key_authorization.as_bytes()).as_ref());
+
+        // Publish the challenge response
+        match (config.challenge, &config.dns_hook) {
+            (AcmeChallenge::TlsAlpn01, _) => resolver.set_challenge_certificate(
+                domain,
+                tls_alpn_challenge_certificate(domain, &key_authorization)?,
+            ),
+            (AcmeChallenge::Dns01, Some(hook)) => {
+                run_dns_hook(hook, "present", &record_name, &record_value).await?;
+                tokio::time::sleep(config.dns_propagation_delay).await;
+            }
+            (AcmeChallenge::Dns01, None) => return Err(anyhow!("dns-01 requires a dns_hook")),
+        }
+
+        tracing::info!("Responding to ACME {} challenge for {}", kind, domain);
+        let result = self
+            .complete_challenge(&challenge.url, authorization_url)
+            .await;
+
+        // Withdraw the challenge response regardless of the outcome
+        match (config.challenge, &config.dns_hook) {
+            (AcmeChallenge::TlsAlpn01, _) => resolver.clear_challenge_certificate(domain),
+            (AcmeChallenge::Dns01, Some(hook)) => {
+                if let Err(e) = run_dns_hook(hook, "cleanup", &record_name, &record_value).await {
+                    tracing::warn!("Failed to clean up ACME DNS record {}: {}", record_name, e);
+                }
+            }
+            (AcmeChallenge::Dns01, None) => {}
+        }
+
+        result
+    }
+
+    async fn complete_challenge(
+        &mut self,
+        challenge_url: &str,
+        authorization_url: &str,
+    ) -> Result<()> {
+        self.post(challenge_url, Some(&json!({}))).await?;
+
+        for _ in 0..POLL_ATTEMPTS {
+            let authorization: Authorization = self.post(authorization_url, None).await?.parse()?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => {
+                    return Err(anyhow!(
+                        "ACME authorization for {} is {}",
+                        authorization.identifier.value,
+                        status
+                    ));
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "Timed out waiting for ACME authorization {}",
+            authorization_url
+        ))
+    }
+
+    /// Poll an order until it reaches `target` (`ready` or `valid`)
+    async fn poll_order(&mut self, order_url: &str, target: &str) -> Result<Order> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.post(order_url, None).await?.parse()?;
+            match order.status.as_str() {
+                status if status == target => return Ok(order),
+                "pending" | "ready" | "processing" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => {
+                    return Err(anyhow!(
+                        "ACME order {} is {}: {}",
+                        order_url,
+                        status,
+                        order.error.unwrap_or_default()
+                    ));
+                }
+            }
+        }
+
+        Err(anyhow!("Timed out waiting for ACME order {}", order_url))
+    }
+}
+
+/// Start background task that issues the listener certificate and renews it before expiry
+pub fn start_acme_task(config: Acme, resolver: Arc<CertResolver>) -> tokio::task::JoinHandle<()> {
+    let mut renew_at = match load_persisted_certificate(&config, &resolver) {
+        Ok(Some(info)) => info.renewal_time(config.renew_before),
+        Ok(None) => SystemTime::now(),
+        Err(e) => {
+            tracing::warn!("Ignoring persisted ACME certificate: {}", e);
+            SystemTime::now()
+        }
+    };
+
+    tokio::spawn(async move {
+        loop {
+            if let Ok(wait) = renew_at.duration_since(SystemTime::now()) {
+                tracing::info!(
+                    "Next ACME renewal for {} in {}s",
+                    config.domains[0],
+                    wait.as_secs()
+                );
+                tokio::time::sleep(wait).await;
+            }
+
+            match obtain_certificate(&config, &resolver).await {
+                Ok(info) => renew_at = info.renewal_time(config.renew_before),
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to obtain ACME certificate for {}: {:#}",
+                        config.domains[0],
+                        e
+                    );
+                    renew_at = SystemTime::now() + RETRY_INTERVAL;
+                }
+            }
+        }
+    })
+}
+
+/// Issue a new certificate, persist it to the state directory and swap it into the resolver
+async fn obtain_certificate(config: &Acme, resolver: &CertResolver) -> Result<CertificateInfo> {
+    let state_dir = Path::new(&config.state_dir);
+    tokio::fs::create_dir_all(state_dir)
+        .await
+        .with_context(|| format!("Failed to create ACME state_dir {}", config.state_dir))?;
+
+    let account_key = load_or_create_account_key(state_dir)?;
+    let mut client = AcmeClient::new(&config.directory_url, &account_key).await?;
+    client.register_account(&config.contact).await?;
+
+    let (cert_pem, key_pem) = client.issue(config, resolver).await?;
+    let certified_key = certified_key_from_pem(&cert_pem, &key_pem)?;
+    let info = certificate_info(&cert_pem)?;
+
+    persist_certificate(config, &cert_pem, &key_pem)?;
+
+    resolver.set_certificate(certified_key);
+    tracing::info!("Installed ACME certificate for {:?}", config.domains);
+    Ok(info)
+}
+
+/// Serve a previously issued certificate from the state directory, if it is still usable
+fn load_persisted_certificate(
+    config: &Acme,
+    resolver: &CertResolver,
+) -> Result<Option<CertificateInfo>> {
+    let (cert_path, key_path) = certificate_paths(config);
+    if !cert_path.exists() || !key_path.exists() {
+        return Ok(None);
+    }
+
+    let cert_pem = std::fs::read_to_string(&cert_path)?;
+    let key_pem = std::fs::read_to_string(&key_path)?;
+    let info = certificate_info(&cert_pem)?;
+    if info.not_after <= SystemTime::now() || !info.covers(&config.domains) {
+        return Ok(None);
+    }
+
+    resolver.set_certificate(certified_key_from_pem(&cert_pem, &key_pem)?);
+    tracing::info!("Loaded ACME certificate from {}", cert_path.display());
+    Ok(Some(info))
+}
+
+/// Replace the persisted certificate and key. Both are written to temporary files first and
+/// renamed into place with the certificate last, so a failure never pairs the old
+/// certificate with the new key.
+fn persist_certificate(config: &Acme, cert_pem: &str, key_pem: &str) -> Result<()> {
+    let (cert_path, key_path) = certificate_paths(config);
+    let temporary = |path: &Path| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".tmp");
+        PathBuf::from(name)
+    };
+    let (cert_temp, key_temp) = (temporary(&cert_path), temporary(&key_path));
+
+    write_private_file(&key_temp, key_pem)?;
+    std::fs::write(&cert_temp, cert_pem)
+        .with_context(|| format!("Failed to write {}", cert_temp.display()))?;
+    std::fs::rename(&key_temp, &key_path)
+        .with_context(|| format!("Failed to replace {}", key_path.display()))?;
+    std::fs::rename(&cert_temp, &cert_path)
+        .with_context(|| format!("Failed to replace {}", cert_path.display()))?;
+    Ok(())
+}
+
+fn certificate_paths(config: &Acme) -> (PathBuf, PathBuf) {
+    let state_dir = Path::new(&config.state_dir);
+    let name = config.domains[0].replace('*', "_");
+    (
+        state_dir.join(format!("{name}.crt")),
+        state_dir.join(format!("{name}.key")),
+    )
+}
+
+/// Load the PKCS#8 account key from the state directory, generating it on first use
+fn load_or_create_account_key(state_dir: &Path) -> Result<Vec<u8>> {
+    let path = state_dir.join("account.key");
+    if path.exists() {
+        let pem = std::fs::read_to_string(&path)?;
+        let key = private_key(&mut BufReader::new(pem.as_bytes()))?
+            .ok_or_else(|| anyhow!("No private key found in {}", path.display()))?;
+        return Ok(key.secret_der().to_vec());
+    }
+
+    let key_pair = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?;
+    write_private_file(&path, &key_pair.serialize_pem())?;
+    tracing::info!("Generated ACME account key {}", path.display());
+    Ok(key_pair.serialize_der())
+}
+
+/// Self-signed certificate carrying the acmeIdentifier extension (RFC 8737)
+fn tls_alpn_challenge_certificate(domain: &str, key_authorization: &str) -> Result<CertifiedKey> {
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    let key_authorization_digest = digest(&SHA256, key_authorization.as_bytes());
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+        key_authorization_digest.as_ref(),
+    )];
+
+    let cert = Certificate::from_params(params)?;
+    certified_key_from_pem(&cert.serialize_pem()?, &cert.serialize_private_key_pem())
+}
+
+async fn run_dns_hook(hook: &str, action: &str, record_name: &str, value: &str) -> Result<()> {
+    run_hook_with_timeout(hook, action, record_name, value, DNS_HOOK_TIMEOUT).await
+}
+
+async fn run_hook_with_timeout(
+    hook: &str,
+    action: &str,
+    record_name: &str,
+    value: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let status = tokio::process::Command::new(hook)
+        .args([action, record_name, value])
+        .kill_on_drop(true)
+        .status();
+    let status = tokio::time::timeout(timeout, status)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "ACME dns_hook {} {} did not finish within {:?}",
+                hook,
+                action,
+                timeout
+            )
+        })?
+        .with_context(|| format!("Failed to run ACME dns_hook {hook}"))?;
+
+    if !status.success() {
+        return Err(anyhow!(
+            "ACME dns_hook {} {} exited with {}",
+            hook,
+            action,
+            status
+        ));
+    }
+    Ok(())
+}
+
+fn certificate_info(cert_pem: &str) -> Result<CertificateInfo> {
+    let der = certs(&mut BufReader::new(cert_pem.as_bytes()))
+        .next()
+        .ok_or_else(|| anyhow!("No certificate found in certificate data"))??;
+    let (_, cert) = x509_parser::parse_x509_certificate(&der)
+        .map_err(|e| anyhow!("Failed to parse certificate: {}", e))?;
+
+    let timestamp = |t: i64| UNIX_EPOCH + Duration::from_secs(t.max(0) as u64);
+    let dns_names = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns) => Some(dns.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(CertificateInfo {
+        not_before: timestamp(cert.validity().not_before.timestamp()),
+        not_after: timestamp(cert.validity().not_after.timestamp()),
+        dns_names,
+    })
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("replay-nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn b64(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_http::{self, MockRequest, MockResponse};
+    use ring::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+    use std::sync::Mutex;
+
+    const DOMAIN: &str = "db.example.com";
+
+    #[derive(Default)]
+    struct MockAcmeState {
+        account_key: Option<Vec<u8>>,
+        challenge_completed: bool,
+        certificate: Option<String>,
+    }
+
+    /// Decode a JWS request body, verifying its signature against the account key
+    fn decode_jws(request: &MockRequest, state: &mut MockAcmeState) -> Value {
+        let jws = request.json();
+        let protected: Value = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(jws["protected"].as_str().unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+
+        if let Some(jwk) = protected.get("jwk") {
+            let mut point = vec![0x04];
+            point.extend(URL_SAFE_NO_PAD.decode(jwk["x"].as_str().unwrap()).unwrap());
+            point.extend(URL_SAFE_NO_PAD.decode(jwk["y"].as_str().unwrap()).unwrap());
+            state.account_key = Some(point);
+        } else {
+            assert!(protected["kid"].as_str().unwrap().ends_with("/account/1"));
+        }
+
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        let signature = URL_SAFE_NO_PAD
+            .decode(jws["signature"].as_str().unwrap())
+            .unwrap();
+        UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            state.account_key.as_ref().unwrap(),
+        )
+        .verify(signed.as_bytes(), &signature)
+        .expect("invalid JWS signature");
+
+        match jws["payload"].as_str().unwrap() {
+            "" => Value::Null,
+            payload => serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap(),
+        }
+    }
+
+    /// Start a Pebble-like ACME server that validates every dns-01 challenge it is asked to
+    async fn start_mock_acme_server() -> String {
+        let ca = Arc::new({
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            Certificate::from_params(params).unwrap()
+        });
+        let state = Arc::new(Mutex::new(MockAcmeState::default()));
+
+        let base_url = mock_http::serve(move |request| {
+            let base = format!("http://{}", request.header("host").unwrap());
+            let mut state = state.lock().unwrap();
+            let nonce = "mock-nonce";
+
+            let order = |state: &MockAcmeState| {
+                json!({
+                    "status": match (&state.certificate, state.challenge_completed) {
+                        (Some(_), _) => "valid",
+                        (None, true) => "ready",
+                        (None, false) => "pending",
+                    },
+                    "authorizations": [format!("{base}/authz/1")],
+                    "finalize": format!("{base}/finalize/1"),
+                    "certificate": format!("{base}/cert/1"),
+                })
+            };
+
+            let response = match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/directory") => MockResponse::json(
+                    200,
+                    json!({
+                        "newNonce": format!("{base}/nonce"),
+                        "newAccount": format!("{base}/account"),
+                        "newOrder": format!("{base}/order"),
+                    }),
+                ),
+                ("HEAD", "/nonce") => MockResponse::text(200, ""),
+                ("POST", "/account") => {
+                    decode_jws(&request, &mut state);
+                    MockResponse::json(201, json!({ "status": "valid" }))
+                        .with_header("Location", &format!("{base}/account/1"))
+                }
+                ("POST", "/order") => {
+                    let payload = decode_jws(&request, &mut state);
+                    assert_eq!(payload["identifiers"][0]["value"], DOMAIN);
+                    MockResponse::json(201, order(&state))
+                        .with_header("Location", &format!("{base}/order/1"))
+                }
+                ("POST", "/order/1") => {
+                    decode_jws(&request, &mut state);
+                    MockResponse::json(200, order(&state))
+                }
+                ("POST", "/authz/1") => {
+                    decode_jws(&request, &mut state);
+                    MockResponse::json(
+                        200,
+                        json!({
+                            "status": if state.challenge_completed { "valid" } else { "pending" },
+                            "identifier": { "type": "dns", "value": DOMAIN },
+                            "challenges": [{
+                                "type": "dns-01",
+                                "url": format!("{base}/challenge/1"),
+                                "token": "mock-token",
+                            }],
+                        }),
+                    )
+                }
+                ("POST", "/challenge/1") => {
+                    decode_jws(&request, &mut state);
+                    state.challenge_completed = true;
+                    MockResponse::json(200, json!({ "status": "processing" }))
+                }
+                ("POST", "/finalize/1") if !state.challenge_completed => {
+                    decode_jws(&request, &mut state);
+                    MockResponse::json(
+                        403,
+                        json!({ "type": "urn:ietf:params:acme:error:orderNotReady" }),
+                    )
+                }
+                ("POST", "/finalize/1") => {
+                    let payload = decode_jws(&request, &mut state);
+                    let csr = URL_SAFE_NO_PAD
+                        .decode(payload["csr"].as_str().unwrap())
+                        .unwrap();
+                    let csr = rcgen::CertificateSigningRequest::from_der(&csr).unwrap();
+                    state.certificate = Some(csr.serialize_pem_with_signer(&ca).unwrap());
+                    MockResponse::json(200, order(&state))
+                }
+                ("POST", "/cert/1") => {
+                    decode_jws(&request, &mut state);
+                    MockResponse::text(200, state.certificate.as_ref().unwrap())
+                }
+                _ => MockResponse::text(404, "not found"),
+            };
+
+            response.with_header("Replay-Nonce", nonce)
+        })
+        .await;
+
+        format!("{base_url}/directory")
+    }
+
+    fn acme_config(directory_url: String, state_dir: &Path) -> Acme {
+        Acme {
+            directory_url,
+            domains: vec![DOMAIN.to_string()],
+            contact: vec!["ops@example.com".to_string()],
+            state_dir: state_dir.to_string_lossy().to_string(),
+            challenge: AcmeChallenge::Dns01,
+            dns_hook: Some("true".to_string()),
+            dns_propagation_delay: Duration::ZERO,
+            renew_before: Duration::from_secs(30 * 24 * 3600),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_obtain_certificate_dns01() {
+        let directory_url = start_mock_acme_server().await;
+        let state_dir = tempfile::tempdir().unwrap();
+        let config = acme_config(directory_url, state_dir.path());
+
+        let resolver = CertResolver::default();
+        let info = obtain_certificate(&config, &resolver).await.unwrap();
+
+        assert!(resolver.has_certificate());
+        assert!(info.covers(&config.domains));
+        assert!(info.renewal_time(config.renew_before) > SystemTime::now());
+        assert!(state_dir.path().join("account.key").exists());
+        let (cert_path, key_path) = certificate_paths(&config);
+        assert!(cert_path.exists() && key_path.exists());
+        let leftovers = std::fs::read_dir(state_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("tmp".as_ref()))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // A restart serves the persisted certificate without contacting the ACME server
+        let resolver = CertResolver::default();
+        let persisted = load_persisted_certificate(&config, &resolver).unwrap();
+        assert!(persisted.is_some());
+        assert!(resolver.has_certificate());
+    }
+
+    #[test]
+    fn test_persisted_certificate_for_other_domains_is_ignored() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let mut config = acme_config(
+            "https://acme.invalid/directory".to_string(),
+            state_dir.path(),
+        );
+
+        let cert =
+            Certificate::from_params(CertificateParams::new(vec![DOMAIN.to_string()])).unwrap();
+        let (cert_path, key_path) = certificate_paths(&config);
+        std::fs::write(cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(key_path, cert.serialize_private_key_pem()).unwrap();
+
+        config.domains.push("replica.example.com".to_string());
+        let resolver = CertResolver::default();
+        assert!(
+            load_persisted_certificate(&config, &resolver)
+                .unwrap()
+                .is_none()
+        );
+        assert!(!resolver.has_certificate());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_dns_hook_times_out() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let hook = directory.path().join("hook.sh");
+        std::fs::write(&hook, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let started = std::time::Instant::now();
+        let result = run_hook_with_timeout(
+            hook.to_str().unwrap(),
+            "present",
+            "_acme-challenge.db.example.com",
+            "value",
+            Duration::from_millis(200),
+        )
+        .await;
+        assert!(result.unwrap_err().to_string().contains("did not finish"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_tls_alpn_challenge_certificate() {
+        let certified_key = tls_alpn_challenge_certificate(DOMAIN, "token.thumbprint").unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&certified_key.cert[0]).unwrap();
+
+        // id-pe-acmeIdentifier, which must be critical
+        let extension = cert
+            .extensions()
+            .iter()
+            .find(|ext| ext.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .expect("missing acmeIdentifier extension");
+        assert!(extension.critical);
+        assert!(
+            extension
+                .value
+                .ends_with(digest(&SHA256, b"token.thumbprint").as_ref())
+        );
+    }
+}
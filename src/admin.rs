@@ -0,0 +1,251 @@
+use crate::config;
+use crate::connections::Connections;
+use crate::metrics;
+use crate::proxy::ACCEPT_ERROR_DELAY;
+use crate::rate_limit::RateLimiter;
+use crate::routes::Health;
+use crate::upgrade::ListenerSockets;
+use anyhow::Result;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+/// What the admin endpoints report on
+#[derive(Clone)]
+pub struct AdminState {
+    pub health: Health,
+    pub connections: Connections,
+    pub rate_limiter: RateLimiter,
+}
+
+/// Bind the admin listener, reusing the socket of the previous process after an upgrade
+pub async fn bind(admin: &config::Admin, sockets: &ListenerSockets) -> Result<TcpListener> {
+    #[cfg(unix)]
+    if let Some(socket) = sockets.take_inherited(&admin.bind_address) {
+        tracing::info!(
+            "Using inherited socket for admin listener {}",
+            admin.bind_address
+        );
+        let listener = std::net::TcpListener::from(socket);
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        sockets.register(&admin.bind_address, &listener)?;
+        return Ok(listener);
+    }
+    let listener = TcpListener::bind(&admin.bind_address).await?;
+    #[cfg(unix)]
+    sockets.register(&admin.bind_address, &listener)?;
+    #[cfg(not(unix))]
+    let _ = sockets;
+    Ok(listener)
+}
+
+/// Serve admin requests until `shutdown` is cancelled
+pub async fn serve(
+    listener: TcpListener,
+    bind_address: String,
+    state: AdminState,
+    sockets: ListenerSockets,
+    shutdown: CancellationToken,
+) {
+    tracing::info!("Admin endpoint listening on {}", bind_address);
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept admin connection: {}", e);
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = tokio::time::sleep(ACCEPT_ERROR_DELAY) => continue,
+                    }
+                }
+            },
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let response = respond(&request, &state);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Admin connection failed: {}", e);
+            }
+        });
+    }
+    #[cfg(unix)]
+    sockets.unregister(&bind_address);
+    #[cfg(not(unix))]
+    let _ = sockets;
+}
+
+fn respond<B>(request: &Request<B>, state: &AdminState) -> Response<Full<Bytes>> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/health/live") => json_response(StatusCode::OK, json!({"status": "ok"})),
+        (&Method::GET, "/health" | "/health/ready") => {
+            let (status, label) = if state.health.is_ready() {
+                (StatusCode::OK, "ready")
+            } else {
+                (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+            };
+            let body = json!({"status": label, "routes": state.health.routes()});
+            json_response(status, body)
+        }
+        (&Method::GET, "/metrics") => {
+            let body = metrics::render(&state.connections, &state.health);
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            response
+        }
+        (&Method::GET, "/bans") => {
+            json_response(StatusCode::OK, json!({"bans": state.rate_limiter.bans()}))
+        }
+        (&Method::DELETE, "/bans") => {
+            let cleared = state.rate_limiter.clear_bans(None);
+            json_response(StatusCode::OK, json!({"cleared": cleared}))
+        }
+        (&Method::DELETE, path) if path.starts_with("/bans/") => {
+            match path["/bans/".len()..].parse() {
+                Ok(address) => {
+                    let cleared = state.rate_limiter.clear_bans(Some(address));
+                    json_response(StatusCode::OK, json!({"cleared": cleared}))
+                }
+                Err(_) => text_response(StatusCode::BAD_REQUEST, "Invalid IP address\n"),
+            }
+        }
+        (_, "/health" | "/health/live" | "/health/ready" | "/metrics" | "/bans") => {
+            text_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed\n")
+        }
+        _ => text_response(StatusCode::NOT_FOUND, "Not Found\n"),
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn text_response(status: StatusCode, body: &'static str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn request(method: Method, path: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(())
+            .unwrap()
+    }
+
+    async fn body(response: Response<Full<Bytes>>) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_respond_reports_route_health() {
+        let state = AdminState {
+            health: Health::default(),
+            connections: Connections::default(),
+            rate_limiter: RateLimiter::default(),
+        };
+
+        let response = respond(&request(Method::GET, "/health/live"), &state);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await["status"], "ok");
+
+        // Not ready without any routes
+        let response = respond(&request(Method::GET, "/health/ready"), &state);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(response).await["status"], "not_ready");
+
+        let response = respond(&request(Method::POST, "/health"), &state);
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = respond(&request(Method::GET, "/metrics"), &state);
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = respond(&request(Method::GET, "/status"), &state);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_respond_lists_and_clears_bans() {
+        let state = AdminState {
+            health: Health::default(),
+            connections: Connections::default(),
+            rate_limiter: RateLimiter::default(),
+        };
+        let listener = config::Listener::for_tests("127.0.0.1:6432", "[ban]\nmax_failures = 1");
+        let client = "192.0.2.1".parse().unwrap();
+        assert!(state.rate_limiter.record_failure(&listener, client));
+
+        let response = respond(&request(Method::GET, "/bans"), &state);
+        let bans = body(response).await;
+        assert_eq!(bans["bans"][0]["address"], "192.0.2.1");
+        assert_eq!(bans["bans"][0]["route"], "127.0.0.1:6432");
+
+        let response = respond(&request(Method::DELETE, "/bans/not-an-address"), &state);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = respond(&request(Method::DELETE, "/bans/192.0.2.1"), &state);
+        assert_eq!(body(response).await["cleared"], 1);
+        assert!(state.rate_limiter.bans().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_serve_answers_http_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = AdminState {
+            health: Health::default(),
+            connections: Connections::default(),
+            rate_limiter: RateLimiter::default(),
+        };
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve(
+            listener,
+            address.to_string(),
+            state,
+            ListenerSockets::default(),
+            shutdown.clone(),
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /health/live HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"status":"ok"}"#));
+
+        shutdown.cancel();
+        server.await.unwrap();
+    }
+}
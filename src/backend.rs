@@ -0,0 +1,482 @@
+use crate::cert_manager::read_pem_certs;
+use crate::config::{Backend, BackendTls, BackendTlsMode};
+use crate::protocol::SSL_REQUEST;
+use crate::tls;
+use anyhow::{Context as _, Result, anyhow};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pemfile::private_key;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+
+const UNIX_PREFIX: &str = "unix:";
+const DEFAULT_PORT: u16 = 5432;
+/// File name prefix of PostgreSQL's Unix socket, followed by the port number
+const SOCKET_FILE_PREFIX: &str = ".s.PGSQL.";
+
+/// Where to reach a backend server
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendAddress {
+    /// `host:port`
+    Tcp(String),
+    /// Path of a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl BackendAddress {
+    /// Parse `host:port`, `unix:/path/.s.PGSQL.5432`, `unix:/dir` or `unix:/dir:5433`.
+    /// A socket directory is resolved the way libpq does, defaulting to port 5432.
+    pub fn parse(address: &str) -> Result<Self> {
+        let Some(path) = address.strip_prefix(UNIX_PREFIX) else {
+            return Ok(Self::Tcp(address.to_string()));
+        };
+        if !cfg!(unix) {
+            return Err(anyhow!(
+                "Unix domain socket backends are not supported on this platform"
+            ));
+        }
+        if !path.starts_with('/') {
+            return Err(anyhow!("Unix socket path must be absolute: {}", address));
+        }
+
+        let path = PathBuf::from(path);
+        let is_socket_file = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(SOCKET_FILE_PREFIX));
+        if is_socket_file {
+            return Ok(Self::Unix(path));
+        }
+
+        let path = path.to_string_lossy();
+        let (directory, port) = match path.rsplit_once(':') {
+            Some((directory, port)) => {
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| anyhow!("Invalid port in Unix socket address: {}", address))?;
+                (directory, port)
+            }
+            None => (path.as_ref(), DEFAULT_PORT),
+        };
+        Ok(Self::Unix(
+            PathBuf::from(directory).join(format!("{SOCKET_FILE_PREFIX}{port}")),
+        ))
+    }
+
+    pub async fn connect(&self) -> Result<BackendStream> {
+        match self {
+            Self::Tcp(address) => Ok(BackendStream::Tcp(TcpStream::connect(address).await?)),
+            #[cfg(unix)]
+            Self::Unix(path) => Ok(BackendStream::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Self::Unix(_) => Err(anyhow!(
+                "Unix domain socket backends are not supported on this platform"
+            )),
+        }
+    }
+}
+
+/// Name used for SNI and certificate verification: `server_name`, or else the backend host
+pub fn server_name(address: &str, tls: &BackendTls) -> Result<ServerName<'static>> {
+    let name = match (&tls.server_name, BackendAddress::parse(address)?) {
+        (Some(name), _) => name.clone(),
+        (None, BackendAddress::Tcp(address)) => {
+            let host = address
+                .rsplit_once(':')
+                .map_or(address.as_str(), |(host, _)| host);
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string()
+        }
+        // The name is only sent as SNI when it is not verified
+        (None, BackendAddress::Unix(_)) if tls.mode != BackendTlsMode::VerifyFull => {
+            "localhost".to_string()
+        }
+        (None, BackendAddress::Unix(_)) => {
+            return Err(anyhow!(
+                "required for mode = \"verify-full\" with a unix: backend"
+            ));
+        }
+    };
+    ServerName::try_from(name.clone()).map_err(|_| anyhow!("Invalid server name '{}'", name))
+}
+
+/// Verifies backend certificates as selected by `mode`, like libpq's `sslmode`
+#[derive(Debug)]
+struct BackendVerifier {
+    /// Without a verifier, as in `require` mode, any certificate is accepted
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    verify_name: bool,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for BackendVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let Some(webpki) = &self.webpki else {
+            return Ok(ServerCertVerified::assertion());
+        };
+        // The chain is checked before the name, so a name mismatch means the chain is trusted
+        match webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) if !self.verify_name => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Build the TLS client configuration for connecting to a backend
+fn client_config(backend_tls: &BackendTls) -> Result<ClientConfig> {
+    let provider = Arc::new(tls::default_provider());
+
+    // As in libpq, `require` with a CA bundle verifies the chain like `verify-ca`
+    let webpki = if backend_tls.mode == BackendTlsMode::Require && backend_tls.ca_cert.is_none() {
+        None
+    } else {
+        let mut roots = RootCertStore::empty();
+        if let Some(ca_cert) = &backend_tls.ca_cert {
+            for cert in read_pem_certs(ca_cert)? {
+                roots.add(cert)?;
+            }
+        } else {
+            let native = rustls_native_certs::load_native_certs()
+                .context("Failed to load system root certificates")?;
+            roots.add_parsable_certificates(native);
+        }
+        Some(WebPkiServerVerifier::builder_with_provider(roots.into(), provider.clone()).build()?)
+    };
+    let verifier = BackendVerifier {
+        webpki,
+        verify_name: backend_tls.mode == BackendTlsMode::VerifyFull,
+        algorithms: provider.signature_verification_algorithms,
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let mut config = match (&backend_tls.client_cert, &backend_tls.client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            let chain = read_pem_certs(client_cert)?;
+            let key_content = std::fs::read(client_key)
+                .with_context(|| format!("Failed to read key file {client_key}"))?;
+            let key = private_key(&mut BufReader::new(key_content.as_slice()))?
+                .ok_or_else(|| anyhow!("No private key found in {}", client_key))?;
+            builder.with_client_auth_cert(chain, key)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"postgresql".to_vec()];
+    if let Some(key_log) = tls::key_log(backend_tls.key_log_file.as_deref(), "backend")? {
+        config.key_log = key_log;
+    }
+    Ok(config)
+}
+
+/// Opens connections to a route's backend
+#[derive(Clone)]
+pub struct BackendConnector {
+    address: BackendAddress,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+}
+
+impl BackendConnector {
+    pub fn new(backend: &Backend) -> Result<Self> {
+        let tls = match &backend.tls {
+            Some(backend_tls) => Some((
+                TlsConnector::from(Arc::new(client_config(backend_tls)?)),
+                server_name(&backend.address, backend_tls)?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            address: BackendAddress::parse(&backend.address)?,
+            tls,
+        })
+    }
+
+    pub async fn connect(&self) -> Result<BackendStream> {
+        self.address.connect().await
+    }
+
+    /// Upgrade a new connection to TLS with an SSLRequest, if the backend is configured for it
+    pub async fn start_tls(&self, mut stream: BackendStream) -> Result<BackendStream> {
+        let Some((connector, server_name)) = &self.tls else {
+            return Ok(stream);
+        };
+
+        stream.write_all(&SSL_REQUEST).await?;
+        let mut response = [0u8; 1];
+        stream.read_exact(&mut response).await?;
+        match response[0] {
+            b'S' => {}
+            b'N' => return Err(anyhow!("Backend does not accept TLS connections")),
+            other => {
+                return Err(anyhow!(
+                    "Unexpected response {:?} to SSLRequest from backend",
+                    other as char
+                ));
+            }
+        }
+
+        let tls_stream = connector.connect(server_name.clone(), stream).await?;
+        Ok(BackendStream::Tls(Box::new(tls_stream)))
+    }
+}
+
+/// Connection to a backend server over TCP or a Unix domain socket, optionally with TLS
+pub enum BackendStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tls(Box<TlsStream<BackendStream>>),
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use rustls::ServerConfig;
+    use rustls::pki_types::PrivateKeyDer;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Start a PostgreSQL-like server that accepts an SSLRequest and serves `cert` over TLS
+    async fn serve_tls_backend(cert: &Certificate, signer: &Certificate) -> String {
+        let chain = vec![CertificateDer::from(
+            cert.serialize_der_with_signer(signer).unwrap(),
+        )];
+        let key = PrivateKeyDer::try_from(cert.serialize_private_key_der()).unwrap();
+        let server_config = ServerConfig::builder_with_provider(Arc::new(tls::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut request = [0u8; 8];
+                    stream.read_exact(&mut request).await.unwrap();
+                    assert_eq!(request, SSL_REQUEST);
+                    stream.write_all(b"S").await.unwrap();
+                    if let Ok(mut tls_stream) = acceptor.accept(stream).await {
+                        let _ = tls_stream.write_all(b"ok").await;
+                        let _ = tls_stream.shutdown().await;
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    fn backend(address: &str, tls: &str) -> Backend {
+        toml::from_str(&format!("address = \"{address}\"\n[tls]\n{tls}")).unwrap()
+    }
+
+    async fn round_trip(backend: &Backend) -> Result<Vec<u8>> {
+        let connector = BackendConnector::new(backend)?;
+        let stream = connector.connect().await?;
+        let mut stream = connector.start_tls(stream).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(response)
+    }
+
+    #[test]
+    fn test_parse_backend_address() {
+        assert_eq!(
+            BackendAddress::parse("db.example.com:5432").unwrap(),
+            BackendAddress::Tcp("db.example.com:5432".to_string())
+        );
+        assert_eq!(
+            BackendAddress::parse("unix:/var/run/postgresql/.s.PGSQL.5433").unwrap(),
+            BackendAddress::Unix("/var/run/postgresql/.s.PGSQL.5433".into())
+        );
+        assert_eq!(
+            BackendAddress::parse("unix:/var/run/postgresql").unwrap(),
+            BackendAddress::Unix("/var/run/postgresql/.s.PGSQL.5432".into())
+        );
+        assert_eq!(
+            BackendAddress::parse("unix:/tmp:5433").unwrap(),
+            BackendAddress::Unix("/tmp/.s.PGSQL.5433".into())
+        );
+        assert!(BackendAddress::parse("unix:relative/path").is_err());
+        assert!(BackendAddress::parse("unix:/tmp:port").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backend_tls_modes() {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let cert =
+            Certificate::from_params(CertificateParams::new(vec!["db.internal".to_string()]))
+                .unwrap();
+        let address = serve_tls_backend(&cert, &ca).await;
+
+        let mut ca_file = NamedTempFile::new().unwrap();
+        ca_file
+            .write_all(ca.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        let ca_path = ca_file.path().display();
+
+        // The certificate is not issued for 127.0.0.1, so only verify-full rejects it
+        let verify_full = backend(&address, &format!("ca_cert = \"{ca_path}\""));
+        assert!(round_trip(&verify_full).await.is_err());
+
+        let verify_ca = backend(
+            &address,
+            &format!("mode = \"verify-ca\"\nca_cert = \"{ca_path}\""),
+        );
+        assert_eq!(round_trip(&verify_ca).await.unwrap(), b"ok");
+
+        let named = backend(
+            &address,
+            &format!("ca_cert = \"{ca_path}\"\nserver_name = \"db.internal\""),
+        );
+        assert_eq!(round_trip(&named).await.unwrap(), b"ok");
+
+        // Without a CA bundle, only require accepts the private CA
+        let require = backend(&address, "mode = \"require\"");
+        assert_eq!(round_trip(&require).await.unwrap(), b"ok");
+        let untrusted = backend(&address, "mode = \"verify-ca\"");
+        assert!(round_trip(&untrusted).await.is_err());
+    }
+
+    #[test]
+    fn test_server_name() {
+        let tls: BackendTls = toml::from_str("").unwrap();
+        assert_eq!(
+            server_name("db.example.com:5432", &tls).unwrap(),
+            ServerName::try_from("db.example.com").unwrap()
+        );
+        assert_eq!(
+            server_name("[::1]:5432", &tls).unwrap(),
+            ServerName::try_from("::1").unwrap()
+        );
+        assert!(server_name("unix:/var/run/postgresql", &tls).is_err());
+
+        let require: BackendTls = toml::from_str("mode = \"require\"").unwrap();
+        assert!(server_name("unix:/var/run/postgresql", &require).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_unix_socket() {
+        let directory = tempfile::tempdir().unwrap();
+        let address =
+            BackendAddress::parse(&format!("unix:{}:6543", directory.path().display())).unwrap();
+        let BackendAddress::Unix(path) = &address else {
+            panic!("Expected a Unix socket address");
+        };
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+
+        let mut stream = address.connect().await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buffer = [0u8; 4];
+        server.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+    }
+}
@@ -1,32 +1,575 @@
-use crate::config::Listener;
-use anyhow::{Result, anyhow};
-use rustls::ServerConfig;
+use crate::config::{AcmeChallenge, CertSource, ClientAuth, Listener, RemoteSource};
+use crate::tls;
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use reqwest::StatusCode;
+use reqwest::header::{ETAG, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
 use rustls_pemfile::{certs, private_key};
-use rustls_pki_types::CertificateDer;
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use std::collections::HashMap;
 use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
+/// ALPN protocol negotiated by ACME TLS-ALPN-01 validation requests (RFC 8737)
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+/// ALPN protocol sent by PostgreSQL clients (libpq 17+)
+const POSTGRESQL_ALPN_PROTOCOL: &[u8] = b"postgresql";
+const FETCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_FETCH_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Resolves the listener certificate, allowing it to be replaced while connections are served
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    /// Swap in a new certificate for all subsequent handshakes
+    pub fn set_certificate(&self, certified_key: CertifiedKey) {
+        *self.current.write().unwrap() = Some(Arc::new(certified_key));
+    }
+
+    pub fn has_certificate(&self) -> bool {
+        self.current.read().unwrap().is_some()
+    }
+
+    /// Serve a TLS-ALPN-01 challenge certificate for the given domain
+    pub fn set_challenge_certificate(&self, domain: &str, certified_key: CertifiedKey) {
+        self.challenges
+            .write()
+            .unwrap()
+            .insert(domain.to_string(), Arc::new(certified_key));
+    }
+
+    pub fn clear_challenge_certificate(&self, domain: &str) {
+        self.challenges.write().unwrap().remove(domain);
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let is_acme_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN_PROTOCOL));
+
+        if is_acme_challenge {
+            let domain = client_hello.server_name()?;
+            return self.challenges.read().unwrap().get(domain).cloned();
+        }
+
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// Build a certified key from PEM encoded certificate chain and private key
+pub fn certified_key_from_pem(cert_pem: &str, key_pem: &str) -> Result<CertifiedKey> {
+    let cert_chain: Vec<CertificateDer> =
+        certs(&mut BufReader::new(cert_pem.as_bytes())).collect::<Result<Vec<_>, _>>()?;
+    if cert_chain.is_empty() {
+        return Err(anyhow!("No certificate found in certificate data"));
+    }
+
+    let private_key = private_key(&mut BufReader::new(key_pem.as_bytes()))?
+        .ok_or_else(|| anyhow!("No private key found in key data"))?;
+    let signing_key = tls::default_provider()
+        .key_provider
+        .load_private_key(private_key)
+        .map_err(|e| anyhow!("Unsupported private key: {}", e))?;
+
+    // The check `CertifiedKey::keys_match` makes, with a parser that also accepts v1
+    // certificates and the critical extension of ACME challenge certificates. Keys that cannot
+    // report their public key are trusted, as `with_single_cert` does.
+    if let Some(key_spki) = signing_key.public_key() {
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert_chain[0])
+            .map_err(|e| anyhow!("Failed to parse certificate: {}", e))?;
+        if key_spki.as_ref() != parsed.tbs_certificate.subject_pki.raw {
+            return Err(anyhow!("Private key does not match the certificate"));
+        }
+    }
+
+    Ok(CertifiedKey::new(cert_chain, signing_key))
+}
+
+/// Subject common name of a certificate, if it has one
+pub fn certificate_common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let common_name = parsed.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(str::to_string)
+}
+
+/// Full subject distinguished name of a certificate
+pub fn certificate_subject(cert: &CertificateDer) -> Option<String> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(parsed.subject().to_string())
+}
+
+/// Identity of a certificate holder: the subject common name, or the full subject if it has none
+pub fn certificate_identity(cert: &CertificateDer) -> Option<String> {
+    certificate_common_name(cert).or_else(|| certificate_subject(cert))
+}
+
+/// Base64 SHA-256 digest of a certificate's SubjectPublicKeyInfo, as used by `pin_sha256`
+pub fn spki_sha256(cert: &CertificateDer) -> Result<String> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| anyhow!("Failed to parse certificate: {}", e))?;
+    let digest = ring::digest::digest(
+        &ring::digest::SHA256,
+        parsed.tbs_certificate.subject_pki.raw,
+    );
+    Ok(STANDARD.encode(digest))
+}
+
+/// Accepts a server certificate only if its chain contains a pinned public key.
+/// Without a CA bundle the pin is the only trust anchor, which suits self-signed endpoints.
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<String>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| spki_sha256(cert).ok())
+            .any(|digest| self.pins.contains(&digest));
+        if pinned {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server public key does not match any pin_sha256 entry".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Read a secret from an environment variable or file, trimming trailing whitespace
+fn read_secret(env: Option<&str>, file: Option<&str>) -> Result<Option<String>> {
+    if let Some(name) = env {
+        let value =
+            std::env::var(name).map_err(|_| anyhow!("Environment variable {} is not set", name))?;
+        return Ok(Some(value.trim().to_string()));
+    }
+    if let Some(path) = file {
+        let value = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read secret file {path}"))?;
+        return Ok(Some(value.trim().to_string()));
+    }
+    Ok(None)
+}
+
+pub fn read_pem_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let content =
+        std::fs::read(path).with_context(|| format!("Failed to read certificate file {path}"))?;
+    let certs = certs(&mut BufReader::new(content.as_slice())).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path));
+    }
+    Ok(certs)
+}
+
+/// Roots trusted by every HTTPS client without a CA bundle of its own: the Mozilla roots that
+/// reqwest ships with
+fn default_roots() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+/// An HTTP client whose TLS uses the configured crypto provider, trusting the default roots
+/// plus `extra_roots`
+pub fn http_client(extra_roots: Vec<CertificateDer<'static>>) -> Result<reqwest::Client> {
+    let mut roots = default_roots();
+    for cert in extra_roots {
+        roots.add(cert)?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(tls::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .use_preconfigured_tls(config)
+        .build()?)
+}
+
+/// Build the TLS client configuration for fetching from a remote source
+fn remote_tls_config(remote: &RemoteSource) -> Result<ClientConfig> {
+    let provider = Arc::new(tls::default_provider());
+
+    let mut roots = RootCertStore::empty();
+    if let Some(ca_cert) = &remote.ca_cert {
+        for cert in read_pem_certs(ca_cert)? {
+            roots.add(cert)?;
+        }
+    } else if remote.pin_sha256.is_empty() {
+        roots = default_roots();
+    }
+
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = if remote.pin_sha256.is_empty() {
+        builder.with_root_certificates(roots)
+    } else {
+        let webpki = match remote.ca_cert {
+            Some(_) => Some(
+                WebPkiServerVerifier::builder_with_provider(roots.into(), provider.clone())
+                    .build()?,
+            ),
+            None => None,
+        };
+        let pins = remote.pins().map(str::to_string).collect();
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedServerVerifier {
+                webpki,
+                pins,
+                algorithms: provider.signature_verification_algorithms,
+            }))
+    };
+
+    let config = match (&remote.client_cert, &remote.client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            let chain = read_pem_certs(client_cert)?;
+            let key_content = std::fs::read(client_key)
+                .with_context(|| format!("Failed to read key file {client_key}"))?;
+            let key = private_key(&mut BufReader::new(key_content.as_slice()))?
+                .ok_or_else(|| anyhow!("No private key found in {}", client_key))?;
+            builder.with_client_auth_cert(chain, key)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+/// TLS settings of a remote source: CA bundle, pins, client certificate and key
+type TlsOptions = (Option<String>, Vec<String>, Option<String>, Option<String>);
+
+fn tls_options(remote: &RemoteSource) -> TlsOptions {
+    (
+        remote.ca_cert.clone(),
+        remote.pin_sha256.clone(),
+        remote.client_cert.clone(),
+        remote.client_key.clone(),
+    )
+}
+
+/// Validators and body of the last successful response for a URL
+struct CachedResponse {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    content: String,
+}
+
+enum FetchError {
+    /// Connection failures and server errors, worth retrying
+    Transient(anyhow::Error),
+    Permanent(anyhow::Error),
+}
+
+/// Fetches certificate material over HTTP with retries, conditional requests
+/// and an optional last-known-good copy on disk
+#[derive(Clone)]
+pub struct RemoteFetcher {
+    client: reqwest::Client,
+    /// Clients for sources with TLS options, built on first use so that retries and
+    /// refreshes reuse their connections
+    tls_clients: Arc<Mutex<HashMap<TlsOptions, reqwest::Client>>>,
+    retries: u32,
+    cache_dir: Option<PathBuf>,
+    responses: Arc<Mutex<HashMap<String, CachedResponse>>>,
+}
+
+impl RemoteFetcher {
+    pub fn new(retries: u32, cache_dir: Option<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            client: http_client(Vec::new())?,
+            tls_clients: Arc::new(Mutex::new(HashMap::new())),
+            retries,
+            cache_dir,
+            responses: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// The HTTP client honouring a remote source's CA bundle, pins and client certificate.
+    /// Files it names are read once, when the first source with these settings is fetched.
+    fn client(&self, remote: &RemoteSource) -> Result<reqwest::Client> {
+        if !remote.has_tls_options() {
+            return Ok(self.client.clone());
+        }
+        let mut clients = self.tls_clients.lock().unwrap();
+        let options = tls_options(remote);
+        if let Some(client) = clients.get(&options) {
+            return Ok(client.clone());
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .use_preconfigured_tls(remote_tls_config(remote)?)
+            .build()?;
+        clients.insert(options, client.clone());
+        Ok(client)
+    }
+
+    /// Fetch a source, retrying transient failures and falling back to the disk cache after
+    /// them. Permanent failures such as a revoked token or a deleted certificate are not
+    /// papered over with stale material.
+    async fn fetch_certificate_content(&self, source: &CertSource) -> Result<String> {
+        let url = source.location();
+        let mut delay = FETCH_RETRY_DELAY;
+        let mut attempt = 0;
+
+        let result = loop {
+            match self.fetch_once(source).await {
+                Ok(content) => break Ok(content),
+                Err(FetchError::Transient(e)) if attempt < self.retries => {
+                    attempt += 1;
+                    tracing::warn!(
+                        "Fetching {} failed (attempt {}/{}), retrying in {:?}: {}",
+                        url,
+                        attempt,
+                        self.retries + 1,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_FETCH_RETRY_DELAY);
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        match result {
+            Ok(content) => Ok(content),
+            Err(FetchError::Permanent(e)) => Err(e),
+            Err(FetchError::Transient(e)) => match self.read_cached(url) {
+                Some(content) => {
+                    tracing::warn!("Using cached copy of {} after fetch failure: {}", url, e);
+                    Ok(content)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn fetch_once(&self, source: &CertSource) -> Result<String, FetchError> {
+        let url = source.location();
+        let mut request = match source {
+            CertSource::Location(_) => self.client.get(url),
+            CertSource::Remote(remote) => self
+                .authenticated_request(remote)
+                .map_err(FetchError::Permanent)?,
+        };
+
+        if let Some(cached) = self.responses.lock().unwrap().get(url) {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request.send().await.map_err(|e| {
+            FetchError::Transient(anyhow!("Failed to fetch certificate from {}: {}", url, e))
+        })?;
+
+        let status = response.status();
+        if status == StatusCode::NOT_MODIFIED {
+            tracing::debug!("Certificate at {} is unchanged", url);
+            return match self.responses.lock().unwrap().get(url) {
+                Some(cached) => Ok(cached.content.clone()),
+                None => Err(FetchError::Permanent(anyhow!(
+                    "Unexpected 304 Not Modified when fetching certificate from {}",
+                    url
+                ))),
+            };
+        }
+        if !status.is_success() {
+            let e = anyhow!(
+                "HTTP error {} when fetching certificate from {}",
+                status,
+                url
+            );
+            return Err(if status.is_server_error() {
+                FetchError::Transient(e)
+            } else {
+                FetchError::Permanent(e)
+            });
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+
+        let content = response.text().await.map_err(|e| {
+            FetchError::Transient(anyhow!(
+                "Failed to read certificate content from {}: {}",
+                url,
+                e
+            ))
+        })?;
+
+        self.write_cached(url, &content);
+        self.responses.lock().unwrap().insert(
+            url.to_string(),
+            CachedResponse {
+                etag,
+                last_modified,
+                content: content.clone(),
+            },
+        );
+        Ok(content)
+    }
+
+    /// Build a request carrying the source's headers and credentials
+    fn authenticated_request(&self, remote: &RemoteSource) -> Result<reqwest::RequestBuilder> {
+        let mut request = self.client(remote)?.get(&remote.url);
+        for (name, value) in &remote.headers {
+            request = request.header(name, value);
+        }
+        if let Some(token) = read_secret(
+            remote.bearer_token_env.as_deref(),
+            remote.bearer_token_file.as_deref(),
+        )? {
+            request = request.bearer_auth(token);
+        }
+        if let Some(basic_auth) = &remote.basic_auth {
+            let password = read_secret(
+                basic_auth.password_env.as_deref(),
+                basic_auth.password_file.as_deref(),
+            )?;
+            request = request.basic_auth(&basic_auth.username, password);
+        }
+        Ok(request)
+    }
+
+    fn cache_path(&self, url: &str) -> Option<PathBuf> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, url.as_bytes());
+        let name: String = digest.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+        Some(self.cache_dir.as_ref()?.join(format!("{name}.pem")))
+    }
+
+    fn read_cached(&self, url: &str) -> Option<String> {
+        std::fs::read_to_string(self.cache_path(url)?).ok()
+    }
+
+    /// Keep a last-known-good copy; failures only cost the fallback, so they are logged
+    fn write_cached(&self, url: &str, content: &str) {
+        let Some(path) = self.cache_path(url) else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| write_private_file(&path, content));
+        if let Err(e) = result {
+            tracing::warn!("Failed to cache certificate from {}: {}", url, e);
+        }
+    }
+}
+
+/// Write a file readable only by the owner, as it may hold private keys
+pub fn write_private_file(path: &Path, content: &str) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    std::io::Write::write_all(&mut file, content.as_bytes())?;
+    Ok(())
+}
+
 /// Certificate manager handles loading and refreshing certificates from various sources
 pub struct CertificateManager {
-    http_client: reqwest::Client,
+    fetcher: RemoteFetcher,
+    resolver: Arc<CertResolver>,
 }
 
 impl CertificateManager {
-    /// Create a new certificate manager
-    pub fn new() -> Result<Self> {
-        let http_client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()?;
+    /// Create a new certificate manager for a listener
+    pub fn new(listener_config: &Listener) -> Result<Self> {
+        let fetcher = RemoteFetcher::new(
+            listener_config.cert_fetch_retries,
+            listener_config.cert_cache_dir.as_ref().map(PathBuf::from),
+        )?;
 
-        Ok(Self { http_client })
+        Ok(Self {
+            fetcher,
+            resolver: Arc::new(CertResolver::default()),
+        })
+    }
+
+    /// The resolver backing every server config created by this manager
+    pub fn resolver(&self) -> Arc<CertResolver> {
+        self.resolver.clone()
     }
 
     /// Load certificate content from either file or URL
-    pub async fn load_certificate(&self, path: &str) -> Result<String> {
-        if path.starts_with("http://") || path.starts_with("https://") {
-            self.load_from_url(path).await
+    pub async fn load_certificate(&self, source: &CertSource) -> Result<String> {
+        if source.is_url() {
+            self.load_from_url(source).await
         } else {
-            self.load_from_file(path).await
+            self.load_from_file(source.location()).await
         }
     }
 
@@ -39,46 +582,30 @@ impl CertificateManager {
     }
 
     /// Load certificate from URL
-    async fn load_from_url(&self, url: &str) -> Result<String> {
-        tracing::info!("Fetching certificate from URL: {}", url);
-        let response = self
-            .http_client
-            .get(url)
-            .send()
-            .await
-            .map_err(|e| anyhow!("Failed to fetch certificate from {}: {}", url, e))?;
-
-        if !response.status().is_success() {
-            return Err(anyhow!(
-                "HTTP error {} when fetching certificate from {}",
-                response.status(),
-                url
-            ));
-        }
-
-        let content = response
-            .text()
-            .await
-            .map_err(|e| anyhow!("Failed to read certificate content from {}: {}", url, e))?;
-
-        tracing::info!("Successfully loaded certificate from URL: {}", url);
+    async fn load_from_url(&self, source: &CertSource) -> Result<String> {
+        tracing::info!("Fetching certificate from URL: {}", source);
+        let content = self.fetcher.fetch_certificate_content(source).await?;
+        tracing::info!("Successfully loaded certificate from URL: {}", source);
         Ok(content)
     }
 
     /// Create server config from certificate sources
     pub async fn create_server_config(&self, listener_config: &Listener) -> Result<ServerConfig> {
-        // Load server certificate
-        let cert_content = self.load_certificate(&listener_config.server_cert).await?;
-        let cert_chain: Vec<CertificateDer> =
-            certs(&mut BufReader::new(cert_content.as_bytes())).collect::<Result<Vec<_>, _>>()?;
-
-        // Load server private key
-        let key_content = self.load_certificate(&listener_config.server_key).await?;
-        let private_key = private_key(&mut BufReader::new(key_content.as_bytes()))?
-            .ok_or_else(|| anyhow!("No private key found in key data"))?;
-
-        let config = if listener_config.mtls {
-            // mTLS enabled - require client certificates
+        // Load server certificate and private key, unless they are issued via ACME later on
+        if let (Some(cert_path), Some(key_path)) =
+            (&listener_config.server_cert, &listener_config.server_key)
+        {
+            let cert_content = self.load_certificate(cert_path).await?;
+            let key_content = self.load_certificate(key_path).await?;
+            self.resolver
+                .set_certificate(certified_key_from_pem(&cert_content, &key_content)?);
+        }
+
+        let (provider, builder) =
+            tls::server_config_builder(listener_config, tls::default_provider())?;
+        let client_auth = listener_config.client_auth();
+        let mut config = if client_auth != ClientAuth::None {
+            // Verify client certificates against the client CA
             if let Some(client_ca_path) = &listener_config.client_ca {
                 let ca_content = self.load_certificate(client_ca_path).await?;
                 let ca_certs: Vec<CertificateDer> =
@@ -90,47 +617,95 @@ impl CertificateManager {
                     client_auth_roots.add(cert)?;
                 }
 
-                let client_cert_verifier =
-                    rustls::server::WebPkiClientVerifier::builder(client_auth_roots.into())
-                        .build()?;
+                let mut verifier_builder =
+                    rustls::server::WebPkiClientVerifier::builder_with_provider(
+                        client_auth_roots.into(),
+                        provider,
+                    );
+                if client_auth == ClientAuth::Optional {
+                    // Clients without a certificate are let through; presented ones must verify
+                    verifier_builder = verifier_builder.allow_unauthenticated();
+                }
+                let client_cert_verifier = verifier_builder.build()?;
 
-                ServerConfig::builder()
+                builder
                     .with_client_cert_verifier(client_cert_verifier)
-                    .with_single_cert(cert_chain, private_key)?
+                    .with_cert_resolver(self.resolver.clone())
             } else {
-                return Err(anyhow!("mTLS enabled but no client_ca specified"));
+                return Err(anyhow!(
+                    "Client authentication enabled but no client_ca specified"
+                ));
             }
         } else {
             // No client authentication required
-            ServerConfig::builder()
+            builder
                 .with_no_client_auth()
-                .with_single_cert(cert_chain, private_key)?
+                .with_cert_resolver(self.resolver.clone())
         };
 
+        if let Some(key_log) = tls::key_log(
+            listener_config.key_log_file.as_deref(),
+            &format!("listener {}", listener_config.bind_address),
+        )? {
+            config.key_log = key_log;
+        }
+
+        config.session_storage = tls::session_storage(listener_config.session_cache_size);
+        if listener_config.session_tickets {
+            config.ticketer = tls::ticketer(listener_config)?;
+        }
+
+        // ACME validation servers negotiate acme-tls/1; once any protocol is advertised,
+        // clients offering ALPN must find a match, so keep accepting PostgreSQL clients too
+        if listener_config
+            .acme
+            .as_ref()
+            .is_some_and(|acme| acme.challenge == AcmeChallenge::TlsAlpn01)
+        {
+            config.alpn_protocols = vec![
+                POSTGRESQL_ALPN_PROTOCOL.to_vec(),
+                ACME_TLS_ALPN_PROTOCOL.to_vec(),
+            ];
+        }
+
         Ok(config)
     }
 
     /// Helper function to refresh a single certificate
-    async fn refresh_certificate(client: &reqwest::Client, path: &str) -> Result<()> {
-        if path.starts_with("http://") || path.starts_with("https://") {
-            tracing::info!("Refreshing certificate from URL: {}", path);
-            let _content = Self::fetch_certificate_content(client, path).await?;
-            tracing::info!("Successfully refreshed certificate from URL: {}", path);
-            // Certificate content is validated but not stored (just checking accessibility)
-            Ok(())
+    async fn refresh_certificate(fetcher: &RemoteFetcher, source: &CertSource) -> Result<String> {
+        if source.is_url() {
+            tracing::info!("Refreshing certificate from URL: {}", source);
+            let content = fetcher.fetch_certificate_content(source).await?;
+            tracing::info!("Successfully refreshed certificate from URL: {}", source);
+            Ok(content)
         } else {
+            let path = source.location();
             tracing::info!("Refreshing certificate from file: {}", path);
-            tokio::fs::read_to_string(path)
+            let content = tokio::fs::read_to_string(path)
                 .await
                 .map_err(|e| anyhow!("Failed to read certificate file {}: {}", path, e))?;
             tracing::info!("Successfully refreshed certificate from file: {}", path);
-            Ok(())
+            Ok(content)
         }
     }
 
+    /// Helper function to reload the server certificate and key as a pair. They are fetched
+    /// separately and either may come from the cache, so `certified_key_from_pem` checking that
+    /// they match keeps a new certificate from being paired with an old key.
+    async fn refresh_certified_key(
+        fetcher: &RemoteFetcher,
+        cert_path: &CertSource,
+        key_path: &CertSource,
+    ) -> Result<CertifiedKey> {
+        let cert_content = Self::refresh_certificate(fetcher, cert_path).await?;
+        let key_content = Self::refresh_certificate(fetcher, key_path).await?;
+        certified_key_from_pem(&cert_content, &key_content)
+    }
+
     /// Start background task to refresh certificates periodically
     pub fn start_refresh_task(&self, listener_config: &Listener) -> tokio::task::JoinHandle<()> {
-        let http_client = self.http_client.clone();
+        let fetcher = self.fetcher.clone();
+        let resolver = self.resolver.clone();
         let refresh_interval = listener_config.cert_refresh_interval;
         let server_cert = listener_config.server_cert.clone();
         let server_key = listener_config.server_key.clone();
@@ -143,64 +718,372 @@ impl CertificateManager {
             loop {
                 interval.tick().await;
 
-                // Refresh server certificate
-                if let Err(e) = Self::refresh_certificate(&http_client, &server_cert).await {
-                    tracing::error!(
-                        "Failed to refresh server certificate {}: {}",
-                        server_cert,
-                        e
-                    );
-                }
-
-                // Refresh server key
-                if let Err(e) = Self::refresh_certificate(&http_client, &server_key).await {
-                    tracing::error!("Failed to refresh server key {}: {}", server_key, e);
+                // Refresh server certificate and key, swapping them into the running config
+                if let (Some(server_cert), Some(server_key)) = (&server_cert, &server_key) {
+                    match Self::refresh_certified_key(&fetcher, server_cert, server_key).await {
+                        Ok(certified_key) => resolver.set_certificate(certified_key),
+                        Err(e) => tracing::error!(
+                            "Failed to refresh server certificate {}: {}",
+                            server_cert,
+                            e
+                        ),
+                    }
                 }
 
-                // Refresh client CA if present
+                // Refresh client CA if present (checked for accessibility only)
                 if let Some(ca_path) = &client_ca
-                    && let Err(e) = Self::refresh_certificate(&http_client, ca_path).await
+                    && let Err(e) = Self::refresh_certificate(&fetcher, ca_path).await
                 {
                     tracing::error!("Failed to refresh client CA {}: {}", ca_path, e);
                 }
             }
         })
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BasicAuth;
+    use crate::mock_http::{self, MockResponse};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::NamedTempFile;
+
+    fn create_temp_file(content: &str) -> NamedTempFile {
+        let temp_file = NamedTempFile::new().unwrap();
+        std::fs::write(temp_file.path(), content).unwrap();
+        temp_file
+    }
+
+    fn listener() -> Listener {
+        Listener::for_tests("127.0.0.1:0", "")
+    }
+
+    fn remote_source(url: &str) -> RemoteSource {
+        RemoteSource {
+            url: url.to_string(),
+            headers: HashMap::new(),
+            bearer_token_env: None,
+            bearer_token_file: None,
+            basic_auth: None,
+            ca_cert: None,
+            pin_sha256: Vec::new(),
+            client_cert: None,
+            client_key: None,
+        }
+    }
+
+    fn create_ca() -> Certificate {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Certificate::from_params(params).unwrap()
+    }
+
+    /// Start a TLS mock serving `cert`, optionally requiring client certificates issued by `client_ca`
+    async fn serve_certificate_endpoint(
+        cert: &Certificate,
+        signer: &Certificate,
+        client_ca: Option<&Certificate>,
+    ) -> String {
+        let cert_pem = cert.serialize_pem_with_signer(signer).unwrap();
+        let certified_key =
+            certified_key_from_pem(&cert_pem, &cert.serialize_private_key_pem()).unwrap();
+        let resolver = Arc::new(CertResolver::default());
+        resolver.set_certificate(certified_key);
+
+        let provider = Arc::new(tls::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let server_config = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add(CertificateDer::from(ca.serialize_der().unwrap()))
+                    .unwrap();
+                let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+                    roots.into(),
+                    provider,
+                )
+                .build()
+                .unwrap();
+                builder
+                    .with_client_cert_verifier(verifier)
+                    .with_cert_resolver(resolver)
+            }
+            None => builder.with_no_client_auth().with_cert_resolver(resolver),
+        };
+
+        mock_http::serve_tls(Arc::new(server_config), |_| {
+            MockResponse::text(200, "certificate data")
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with_headers_and_credentials() {
+        let base_url = mock_http::serve(|request| {
+            if request.header("x-tenant") != Some("db") {
+                return MockResponse::text(400, "missing tenant header");
+            }
+            match (request.path.as_str(), request.header("authorization")) {
+                ("/bearer.pem", Some("Bearer secret-token")) => MockResponse::text(200, "bearer"),
+                // "pgtls:hunter2"
+                ("/basic.pem", Some("Basic cGd0bHM6aHVudGVyMg==")) => {
+                    MockResponse::text(200, "basic")
+                }
+                _ => MockResponse::text(401, "unauthorized"),
+            }
+        })
+        .await;
+        let fetcher = RemoteFetcher::new(0, None).unwrap();
+        let token_file = create_temp_file("secret-token\n");
+        let password_file = create_temp_file("hunter2");
 
-    /// Helper function to fetch certificate content from URL
-    async fn fetch_certificate_content(client: &reqwest::Client, url: &str) -> Result<String> {
-        let response = client
-            .get(url)
-            .send()
+        let mut bearer = remote_source(&format!("{base_url}/bearer.pem"));
+        bearer
+            .headers
+            .insert("X-Tenant".to_string(), "db".to_string());
+        bearer.bearer_token_file = Some(token_file.path().display().to_string());
+        let content = fetcher
+            .fetch_certificate_content(&CertSource::Remote(Box::new(bearer)))
             .await
-            .map_err(|e| anyhow!("Failed to fetch certificate from {}: {}", url, e))?;
+            .unwrap();
+        assert_eq!(content, "bearer");
 
-        if !response.status().is_success() {
-            return Err(anyhow!(
-                "HTTP error {} when fetching certificate from {}",
-                response.status(),
-                url
-            ));
+        let mut basic = remote_source(&format!("{base_url}/basic.pem"));
+        basic
+            .headers
+            .insert("X-Tenant".to_string(), "db".to_string());
+        basic.basic_auth = Some(BasicAuth {
+            username: "pgtls".to_string(),
+            password_env: None,
+            password_file: Some(password_file.path().display().to_string()),
+        });
+        let content = fetcher
+            .fetch_certificate_content(&CertSource::Remote(Box::new(basic)))
+            .await
+            .unwrap();
+        assert_eq!(content, "basic");
+
+        let unauthenticated = CertSource::from(format!("{base_url}/bearer.pem").as_str());
+        let result = fetcher.fetch_certificate_content(&unauthenticated).await;
+        assert!(result.unwrap_err().to_string().contains("HTTP error 400"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with_pinned_public_key() {
+        let cert = Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+            .unwrap();
+        let base_url = serve_certificate_endpoint(&cert, &cert, None).await;
+        let fetcher = RemoteFetcher::new(0, None).unwrap();
+        let pin = spki_sha256(&CertificateDer::from(cert.serialize_der().unwrap())).unwrap();
+
+        let mut pinned = remote_source(&format!("{base_url}/server.pem"));
+        pinned.pin_sha256 = vec![format!("sha256//{pin}")];
+        let pinned = CertSource::Remote(Box::new(pinned));
+        for _ in 0..2 {
+            let content = fetcher.fetch_certificate_content(&pinned).await.unwrap();
+            assert_eq!(content, "certificate data");
         }
+        // Both fetches used the same client
+        assert_eq!(fetcher.tls_clients.lock().unwrap().len(), 1);
 
-        let content = response
-            .text()
+        let mut mismatched = remote_source(&format!("{base_url}/server.pem"));
+        mismatched.pin_sha256 = vec!["47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".to_string()];
+        let result = fetcher
+            .fetch_certificate_content(&CertSource::Remote(Box::new(mismatched)))
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with_custom_ca_and_client_certificate() {
+        let ca = create_ca();
+        let server_cert =
+            Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+                .unwrap();
+        let client_cert =
+            Certificate::from_params(CertificateParams::new(vec!["pgtls".to_string()])).unwrap();
+        let base_url = serve_certificate_endpoint(&server_cert, &ca, Some(&ca)).await;
+        let fetcher = RemoteFetcher::new(0, None).unwrap();
+
+        let ca_file = create_temp_file(&ca.serialize_pem().unwrap());
+        let client_cert_file =
+            create_temp_file(&client_cert.serialize_pem_with_signer(&ca).unwrap());
+        let client_key_file = create_temp_file(&client_cert.serialize_private_key_pem());
+
+        let mut anonymous = remote_source(&format!("{base_url}/server.pem"));
+        anonymous.ca_cert = Some(ca_file.path().display().to_string());
+        let result = fetcher
+            .fetch_certificate_content(&CertSource::Remote(Box::new(anonymous.clone())))
+            .await;
+        assert!(result.is_err());
+
+        let mut authenticated = anonymous;
+        authenticated.client_cert = Some(client_cert_file.path().display().to_string());
+        authenticated.client_key = Some(client_key_file.path().display().to_string());
+        let content = fetcher
+            .fetch_certificate_content(&CertSource::Remote(Box::new(authenticated)))
             .await
-            .map_err(|e| anyhow!("Failed to read certificate content from {}: {}", url, e))?;
+            .unwrap();
+        assert_eq!(content, "certificate data");
+    }
 
-        Ok(content)
+    #[tokio::test]
+    async fn test_fetch_retries_and_falls_back_to_cache() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let base_url = mock_http::serve(move |_| {
+            // Fail the first attempt, then serve once, then stay down
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                1 => MockResponse::text(200, "certificate data"),
+                _ => MockResponse::text(503, "unavailable"),
+            }
+        })
+        .await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let source = CertSource::from(format!("{base_url}/server.pem").as_str());
+
+        let fetcher = RemoteFetcher::new(1, Some(cache_dir.path().to_path_buf())).unwrap();
+        let content = fetcher.fetch_certificate_content(&source).await.unwrap();
+        assert_eq!(content, "certificate data");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // A restarted process falls back to the last-known-good copy while the server is down
+        let restarted = RemoteFetcher::new(0, Some(cache_dir.path().to_path_buf())).unwrap();
+        let content = restarted.fetch_certificate_content(&source).await.unwrap();
+        assert_eq!(content, "certificate data");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        let uncached = RemoteFetcher::new(0, None).unwrap();
+        let result = uncached.fetch_certificate_content(&source).await;
+        assert!(result.unwrap_err().to_string().contains("HTTP error 503"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_fetch_permanent_failure_skips_cache() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let base_url = mock_http::serve(move |_| match counter.fetch_add(1, Ordering::SeqCst) {
+            0 => MockResponse::text(200, "certificate data"),
+            _ => MockResponse::text(403, "forbidden"),
+        })
+        .await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let source = CertSource::from(format!("{base_url}/server.pem").as_str());
+
+        let fetcher = RemoteFetcher::new(0, Some(cache_dir.path().to_path_buf())).unwrap();
+        assert!(fetcher.fetch_certificate_content(&source).await.is_ok());
+        let restarted = RemoteFetcher::new(0, Some(cache_dir.path().to_path_buf())).unwrap();
+        let result = restarted.fetch_certificate_content(&source).await;
+        assert!(result.unwrap_err().to_string().contains("HTTP error 403"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rejects_certificate_with_cached_key_of_another() {
+        let old =
+            Certificate::from_params(CertificateParams::new(vec!["db.example.com".to_string()]))
+                .unwrap();
+        let new =
+            Certificate::from_params(CertificateParams::new(vec!["db.example.com".to_string()]))
+                .unwrap();
+        let old_pem = (
+            old.serialize_pem().unwrap(),
+            old.serialize_private_key_pem(),
+        );
+        let new_cert_pem = new.serialize_pem().unwrap();
+        let rotated = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let base_url = mock_http::serve({
+            let rotated = rotated.clone();
+            move |request| match (rotated.load(Ordering::SeqCst), request.path.as_str()) {
+                (false, "/server.pem") => MockResponse::text(200, &old_pem.0),
+                (false, _) => MockResponse::text(200, &old_pem.1),
+                // The certificate is rotated while the key endpoint is down
+                (true, "/server.pem") => MockResponse::text(200, &new_cert_pem),
+                (true, _) => MockResponse::text(503, "unavailable"),
+            }
+        })
+        .await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let fetcher = RemoteFetcher::new(0, Some(cache_dir.path().to_path_buf())).unwrap();
+        let cert = CertSource::from(format!("{base_url}/server.pem").as_str());
+        let key = CertSource::from(format!("{base_url}/server.key").as_str());
+
+        assert!(
+            CertificateManager::refresh_certified_key(&fetcher, &cert, &key)
+                .await
+                .is_ok()
+        );
+        rotated.store(true, Ordering::SeqCst);
+        let error = CertificateManager::refresh_certified_key(&fetcher, &cert, &key)
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("does not match the certificate"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_uses_conditional_requests() {
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let counter = downloads.clone();
+        let base_url = mock_http::serve(move |request| {
+            if request.header("if-none-match") == Some("\"v1\"") {
+                return MockResponse::text(304, "");
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+            MockResponse::text(200, "certificate data").with_header("ETag", "\"v1\"")
+        })
+        .await;
+        let source = CertSource::from(format!("{base_url}/server.pem").as_str());
+        let fetcher = RemoteFetcher::new(0, None).unwrap();
+
+        for _ in 0..3 {
+            let content = fetcher.fetch_certificate_content(&source).await.unwrap();
+            assert_eq!(content, "certificate data");
+        }
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_certificate_identity() {
+        let mut params = CertificateParams::new(vec!["client.example.com".to_string()]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "app-user");
+        let cert = Certificate::from_params(params).unwrap();
+
+        let der = CertificateDer::from(cert.serialize_der().unwrap());
+        assert_eq!(certificate_identity(&der).unwrap(), "app-user");
+        assert_eq!(certificate_common_name(&der).unwrap(), "app-user");
+        assert!(certificate_subject(&der).unwrap().contains("CN=app-user"));
+    }
+
+    #[test]
+    fn test_certified_key_rejects_mismatched_key() {
+        let cert =
+            Certificate::from_params(CertificateParams::new(vec!["db.example.com".to_string()]))
+                .unwrap();
+        let other =
+            Certificate::from_params(CertificateParams::new(vec!["db.example.com".to_string()]))
+                .unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+
+        assert!(certified_key_from_pem(&cert_pem, &cert.serialize_private_key_pem()).is_ok());
+        let error = certified_key_from_pem(&cert_pem, &other.serialize_private_key_pem())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("does not match the certificate"));
+    }
 
     #[tokio::test]
     async fn test_load_from_file() {
-        let manager = CertificateManager::new().unwrap();
+        let manager = CertificateManager::new(&listener()).unwrap();
 
-        let result = manager.load_certificate("fixtures/test-cert.pem").await;
+        let result = manager
+            .load_certificate(&"fixtures/test-cert.pem".into())
+            .await;
         // We expect this to work if the file exists
         if std::path::Path::new("fixtures/test-cert.pem").exists() {
             assert!(result.is_ok());
@@ -1,38 +1,442 @@
 use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ipnet::IpNet;
+use rustls::crypto::CryptoProvider;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
+use std::net::IpAddr;
 use std::path::Path;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     #[serde(default = "default_log_level")]
     pub log_level: String,
+    /// Crypto library backing every TLS configuration
+    #[serde(default)]
+    pub crypto_provider: CryptoProviderKind,
+    /// Refuse to start unless the crypto provider is FIPS-validated
+    #[serde(default)]
+    pub fips: bool,
+    /// How long open connections may keep running after a shutdown signal
+    #[serde(default = "default_shutdown_timeout", with = "parse_duration")]
+    pub shutdown_timeout: std::time::Duration,
+    /// Send clients an `admin_shutdown` error before closing them at the shutdown timeout
+    #[serde(default = "default_true")]
+    pub shutdown_notify_clients: bool,
+    /// What happens when a route stops because of an error
+    #[serde(default)]
+    pub route_failure: RouteFailurePolicy,
+    /// Most client connections open at once across all routes
+    pub max_connections: Option<usize>,
+    /// HTTP endpoint for health checks and metrics
+    pub admin: Option<Admin>,
     #[serde(rename = "proxy", default)]
     pub proxies: Vec<Proxy>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CryptoProviderKind {
+    #[default]
+    Ring,
+    /// Requires the `aws-lc-rs` cargo feature
+    AwsLcRs,
+}
+
+impl fmt::Display for CryptoProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CryptoProviderKind::Ring => "ring",
+            CryptoProviderKind::AwsLcRs => "aws-lc-rs",
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteFailurePolicy {
+    /// Restart the failed route with backoff while the other routes keep running
+    #[default]
+    Restart,
+    /// Shut the whole proxy down
+    Exit,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Admin {
+    pub bind_address: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Proxy {
+    /// Identifies the route in logs and matches systemd `FileDescriptorName=`
+    pub name: Option<String>,
     pub listener: Listener,
     pub backend: Backend,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Listener {
     pub bind_address: String,
-    pub server_cert: String,
-    pub server_key: String,
+    pub server_cert: Option<CertSource>,
+    pub server_key: Option<CertSource>,
     #[serde(default)]
     pub mtls: bool,
-    pub client_ca: Option<String>,
+    /// Client certificate mode; takes precedence over `mtls`
+    pub client_auth: Option<ClientAuth>,
+    pub client_ca: Option<CertSource>,
+    /// With `client_auth = optional`, also accept clients that skip TLS
+    #[serde(default)]
+    pub allow_plaintext: bool,
     #[serde(default = "default_refresh_interval", with = "parse_duration")]
     pub cert_refresh_interval: std::time::Duration,
+    /// Retries for transient failures when fetching certificate URLs
+    #[serde(default = "default_cert_fetch_retries")]
+    pub cert_fetch_retries: u32,
+    /// Directory holding last-known-good copies of fetched certificate material
+    pub cert_cache_dir: Option<String>,
+    /// Refuse to fetch certificates, keys or CAs, or to talk to Vault, over plain `http://`
+    #[serde(default)]
+    pub require_https_for_keys: bool,
+    /// Oldest TLS version accepted from clients, `1.2` or `1.3`
+    pub min_tls_version: Option<String>,
+    /// Newest TLS version accepted from clients, `1.2` or `1.3`
+    pub max_tls_version: Option<String>,
+    /// Cipher suites offered, by IANA name; all supported suites when empty
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// Key exchange groups offered, e.g. `X25519`; all supported groups when empty
+    #[serde(default)]
+    pub kx_groups: Vec<String>,
+    /// Offer the X25519MLKEM768 hybrid key exchange, preferred over classical groups
+    #[serde(default)]
+    pub post_quantum: bool,
+    /// Sessions kept for stateful resumption; 0 disables the cache
+    #[serde(default = "default_session_cache_size")]
+    pub session_cache_size: usize,
+    /// Issue stateless session tickets
+    #[serde(default)]
+    pub session_tickets: bool,
+    #[serde(default = "default_ticket_rotation_interval", with = "parse_duration")]
+    pub ticket_rotation_interval: std::time::Duration,
+    /// Secret shared between replicas that ticket keys are derived from
+    pub ticket_key_file: Option<String>,
+    /// Write TLS session secrets here for debugging; `SSLKEYLOGFILE` is honoured otherwise
+    pub key_log_file: Option<String>,
+    /// Expect a PROXY protocol header carrying the real client address
+    pub proxy_protocol: Option<ProxyProtocolMode>,
+    /// Upstream load balancers allowed to send PROXY protocol headers
+    #[serde(default)]
+    pub proxy_protocol_trusted: Vec<IpNet>,
+    /// Permissions of a `unix:` listener socket in octal, e.g. "0660"
+    pub socket_mode: Option<String>,
+    /// User owning a `unix:` listener socket, by name or uid
+    pub socket_owner: Option<String>,
+    /// Group owning a `unix:` listener socket, by name or gid
+    pub socket_group: Option<String>,
+    /// Most client connections open at once on this listener
+    pub max_connections: Option<usize>,
+    /// Most connections open at once from one client IP address
+    pub max_connections_per_client: Option<usize>,
+    /// How long a connection over a limit waits for room before it is rejected
+    #[serde(default, with = "parse_duration")]
+    pub connection_queue_timeout: std::time::Duration,
+    /// Rate of new connections accepted on this listener
+    pub rate_limit: Option<RateLimit>,
+    /// Rate of new connections accepted from one client IP address
+    pub client_rate_limit: Option<RateLimit>,
+    /// Temporarily refuse clients that repeatedly fail the TLS handshake
+    pub ban: Option<Ban>,
+    /// Client networks accepted; all clients not denied when empty
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Client networks refused
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// Which of `allow` and `deny` wins for a client in both
+    #[serde(default)]
+    pub access_order: AccessOrder,
+    /// How long a client has to send its PROXY header, SSLRequest or StartupMessage
+    #[serde(default = "default_setup_timeout", with = "parse_duration")]
+    pub startup_timeout: std::time::Duration,
+    /// How long a client has to complete the TLS handshake
+    #[serde(default = "default_setup_timeout", with = "parse_duration")]
+    pub tls_handshake_timeout: std::time::Duration,
+    /// Close sessions without traffic in either direction for this long; 0 disables
+    #[serde(default, with = "parse_duration")]
+    pub idle_timeout: std::time::Duration,
+    /// Close sessions once they have been open this long; 0 disables
+    #[serde(default, with = "parse_duration")]
+    pub max_connection_lifetime: std::time::Duration,
+    /// Obtain the server certificate from an ACME directory instead of `server_cert`/`server_key`
+    pub acme: Option<Acme>,
+    /// Issue the server certificate from a Vault PKI role instead of `server_cert`/`server_key`
+    pub vault: Option<Vault>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessOrder {
+    /// Denied clients are refused; of the rest, only allowed ones are accepted unless `allow`
+    /// is empty
+    #[default]
+    DenyFirst,
+    /// Allowed clients are accepted, then denied ones are refused; everyone else is accepted
+    AllowFirst,
+}
+
+/// Lowest accepted `rate`, one connection every 1000 seconds
+const MIN_RATE: f64 = 0.001;
+
+/// Token bucket limit on new connections
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RateLimit {
+    /// Connections per second, on average
+    pub rate: f64,
+    /// Connections accepted at once after a quiet period; defaults to `rate` rounded up
+    burst: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.rate.ceil() as u32).max(1)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Ban {
+    /// Failed handshakes within `find_time` that trigger a ban
+    pub max_failures: u32,
+    #[serde(default = "default_ban_time", with = "parse_duration")]
+    pub find_time: std::time::Duration,
+    #[serde(default = "default_ban_time", with = "parse_duration")]
+    pub ban_time: std::time::Duration,
+}
+
+fn default_setup_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(30)
+}
+
+fn default_ban_time() -> std::time::Duration {
+    std::time::Duration::from_secs(600)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_shutdown_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(30)
 }
 
 fn default_refresh_interval() -> std::time::Duration {
     std::time::Duration::from_secs(24 * 3600) // 24 hours
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolMode {
+    V1,
+    V2,
+    /// Accept either version, or no header at all
+    Optional,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Client certificates are not requested
+    None,
+    /// Presented certificates are verified, but clients without one are allowed
+    Optional,
+    /// Clients must present a certificate issued by `client_ca`
+    Required,
+}
+
+fn default_session_cache_size() -> usize {
+    256
+}
+
+fn default_ticket_rotation_interval() -> std::time::Duration {
+    std::time::Duration::from_secs(6 * 3600) // 6 hours
+}
+
+fn default_cert_fetch_retries() -> u32 {
+    3
+}
+
+/// Location of certificate material: a file path or URL, or a table with fetch options
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum CertSource {
+    Location(String),
+    Remote(Box<RemoteSource>),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RemoteSource {
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub bearer_token_env: Option<String>,
+    pub bearer_token_file: Option<String>,
+    pub basic_auth: Option<BasicAuth>,
+    /// CA bundle trusted instead of the system roots
+    pub ca_cert: Option<String>,
+    /// Base64 SHA-256 digests of accepted server SubjectPublicKeyInfo
+    #[serde(default)]
+    pub pin_sha256: Vec<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password_env: Option<String>,
+    pub password_file: Option<String>,
+}
+
+impl CertSource {
+    pub fn location(&self) -> &str {
+        match self {
+            CertSource::Location(location) => location,
+            CertSource::Remote(remote) => &remote.url,
+        }
+    }
+
+    pub fn is_url(&self) -> bool {
+        Listener::is_url(self.location())
+    }
+}
+
+impl From<&str> for CertSource {
+    fn from(location: &str) -> Self {
+        CertSource::Location(location.to_string())
+    }
+}
+
+impl fmt::Display for CertSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.location())
+    }
+}
+
+impl RemoteSource {
+    /// Whether fetching needs a TLS configuration of its own
+    pub fn has_tls_options(&self) -> bool {
+        self.ca_cert.is_some() || !self.pin_sha256.is_empty() || self.client_cert.is_some()
+    }
+
+    /// The `pin_sha256` digests without their optional `sha256//` prefix
+    pub fn pins(&self) -> impl Iterator<Item = &str> {
+        self.pin_sha256
+            .iter()
+            .map(|pin| pin.strip_prefix("sha256//").unwrap_or(pin))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Acme {
+    pub directory_url: String,
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub contact: Vec<String>,
+    pub state_dir: String,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    /// Command invoked as `<hook> present|cleanup <record name> <value>` for dns-01
+    pub dns_hook: Option<String>,
+    #[serde(default = "default_dns_propagation_delay", with = "parse_duration")]
+    pub dns_propagation_delay: std::time::Duration,
+    #[serde(default = "default_renew_before", with = "parse_duration")]
+    pub renew_before: std::time::Duration,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AcmeChallenge {
+    #[default]
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+    #[serde(rename = "dns-01")]
+    Dns01,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Vault {
+    pub address: String,
+    #[serde(default = "default_vault_pki_mount")]
+    pub pki_mount: String,
+    pub role: String,
+    pub common_name: String,
+    #[serde(default)]
+    pub alt_names: Vec<String>,
+    /// Requested certificate TTL, passed to Vault as-is (e.g. "72h")
+    pub ttl: Option<String>,
+    /// Fraction of the certificate TTL after which it is renewed
+    #[serde(default = "default_vault_renew_fraction")]
+    pub renew_fraction: f64,
+    pub namespace: Option<String>,
+    /// CA bundle used to verify the Vault server
+    pub ca_cert: Option<String>,
+    pub auth: VaultAuth,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum VaultAuth {
+    /// Falls back to the VAULT_TOKEN environment variable
+    Token {
+        token: Option<String>,
+        token_file: Option<String>,
+    },
+    AppRole {
+        role_id: String,
+        secret_id: Option<String>,
+        secret_id_file: Option<String>,
+        #[serde(default = "default_approle_mount")]
+        mount: String,
+    },
+    Kubernetes {
+        role: String,
+        #[serde(default = "default_kubernetes_jwt_path")]
+        jwt_path: String,
+        #[serde(default = "default_kubernetes_mount")]
+        mount: String,
+    },
+}
+
+fn default_vault_pki_mount() -> String {
+    "pki".to_string()
+}
+
+fn default_vault_renew_fraction() -> f64 {
+    0.66
+}
+
+fn default_approle_mount() -> String {
+    "approle".to_string()
+}
+
+fn default_kubernetes_jwt_path() -> String {
+    "/var/run/secrets/kubernetes.io/serviceaccount/token".to_string()
+}
+
+fn default_kubernetes_mount() -> String {
+    "kubernetes".to_string()
+}
+
+fn default_dns_propagation_delay() -> std::time::Duration {
+    std::time::Duration::from_secs(30)
+}
+
+fn default_renew_before() -> std::time::Duration {
+    std::time::Duration::from_secs(30 * 24 * 3600) // 30 days
+}
+
 mod parse_duration {
     use serde::{self, Deserialize, Deserializer};
     use std::time::Duration;
@@ -48,7 +452,12 @@ mod parse_duration {
     fn parse_duration_string(s: &str) -> Result<Duration, String> {
         let s = s.trim();
 
-        if let Some(hours_str) = s.strip_suffix('h') {
+        if let Some(days_str) = s.strip_suffix('d') {
+            let days: u64 = days_str
+                .parse()
+                .map_err(|_| format!("Invalid days: {days_str}"))?;
+            Ok(Duration::from_secs(days * 24 * 3600))
+        } else if let Some(hours_str) = s.strip_suffix('h') {
             let hours: u64 = hours_str
                 .parse()
                 .map_err(|_| format!("Invalid hours: {hours_str}"))?;
@@ -73,15 +482,122 @@ mod parse_duration {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Backend {
     pub address: String,
+    /// Send a PROXY protocol v2 header with the client address on each connection
+    #[serde(default)]
+    pub send_proxy_protocol: bool,
+    /// Include the TLS version and client certificate CN in the PROXY header
+    #[serde(default)]
+    pub proxy_protocol_ssl_tlv: bool,
+    /// Templates for rewriting the client's StartupMessage before it reaches the backend
+    pub startup_parameters: Option<StartupParameters>,
+    /// Connect to the backend over TLS, negotiated with an SSLRequest
+    pub tls: Option<BackendTls>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendTlsMode {
+    /// Encrypt without verifying the server certificate
+    Require,
+    /// Verify the certificate chain but not the server name
+    VerifyCa,
+    /// Verify the certificate chain and that it was issued for the server name
+    #[default]
+    VerifyFull,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct BackendTls {
+    #[serde(default)]
+    pub mode: BackendTlsMode,
+    /// CA bundle for verifying the backend; system roots are used otherwise
+    pub ca_cert: Option<String>,
+    /// Client certificate presented to the backend
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// Name sent as SNI and verified against the certificate; defaults to the backend host
+    pub server_name: Option<String>,
+    /// Write TLS session secrets here for debugging; `SSLKEYLOGFILE` is honoured otherwise
+    pub key_log_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct StartupParameters {
+    /// Template for `application_name`; `{application_name}` keeps the client's value
+    pub application_name: Option<String>,
+    /// GUCs appended to `options` as `-c name=value`, with templated values
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
 }
 
 impl Listener {
+    /// A listener on `bind_address` with `settings`, in TOML, on top of the defaults
+    #[cfg(test)]
+    pub fn for_tests(bind_address: &str, settings: &str) -> Self {
+        toml::from_str(&format!("bind_address = \"{bind_address}\"\n{settings}")).unwrap()
+    }
+
     pub fn is_url(path: &str) -> bool {
         path.starts_with("http://") || path.starts_with("https://")
     }
+
+    /// Whether the listener binds a Unix domain socket rather than a TCP address
+    pub fn is_unix(&self) -> bool {
+        self.bind_address.starts_with("unix:")
+    }
+
+    /// Whether `allow`, `deny` and `access_order` let `client` connect
+    pub fn is_allowed(&self, client: IpAddr) -> bool {
+        // Dual-stack listeners see IPv4 clients as IPv4-mapped IPv6 addresses
+        let client = client.to_canonical();
+        let allowed = self.allow.iter().any(|net| net.contains(&client));
+        let denied = self.deny.iter().any(|net| net.contains(&client));
+        match self.access_order {
+            AccessOrder::DenyFirst => !denied && (allowed || self.allow.is_empty()),
+            AccessOrder::AllowFirst => allowed || !denied,
+        }
+    }
+
+    /// Whether the listener has a certificate to terminate TLS with
+    pub fn has_tls(&self) -> bool {
+        self.server_cert.is_some() || self.acme.is_some() || self.vault.is_some()
+    }
+
+    /// Parsed `socket_mode`
+    pub fn socket_mode(&self) -> Result<Option<u32>> {
+        let Some(mode) = &self.socket_mode else {
+            return Ok(None);
+        };
+        match u32::from_str_radix(mode, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(Some(mode)),
+            _ => Err(anyhow!(
+                "Invalid socket_mode '{}', expected e.g. \"0660\"",
+                mode
+            )),
+        }
+    }
+
+    /// Effective client certificate mode, honouring the legacy `mtls` flag
+    pub fn client_auth(&self) -> ClientAuth {
+        match self.client_auth {
+            Some(client_auth) => client_auth,
+            None if self.mtls => ClientAuth::Required,
+            None => ClientAuth::None,
+        }
+    }
+
+    /// Whether clients may connect without TLS. Listeners that check client certificates
+    /// refuse them, unless `allow_plaintext` opts in for `optional` mode.
+    pub fn accepts_plaintext(&self) -> bool {
+        match self.client_auth() {
+            ClientAuth::None => true,
+            ClientAuth::Optional => self.allow_plaintext,
+            ClientAuth::Required => false,
+        }
+    }
 }
 
 fn default_log_level() -> String {
@@ -109,9 +625,64 @@ impl Config {
             return Err(anyhow!("At least one proxy configuration is required"));
         }
 
+        let provider = crate::tls::select_crypto_provider(self)?;
+
+        if self.max_connections == Some(0) {
+            return Err(anyhow!("max_connections must be greater than 0"));
+        }
+
+        if let Some(admin) = &self.admin {
+            admin
+                .bind_address
+                .parse::<std::net::SocketAddr>()
+                .map_err(|e| anyhow!("admin.bind_address must be an IP address and port: {e}"))?;
+            if let Some(i) = self
+                .proxies
+                .iter()
+                .position(|proxy| proxy.listener.bind_address == admin.bind_address)
+            {
+                return Err(anyhow!(
+                    "admin.bind_address '{}' is already used by proxy[{i}]",
+                    admin.bind_address
+                ));
+            }
+        }
+
         for (i, proxy) in self.proxies.iter().enumerate() {
-            proxy.validate_listener(i)?;
+            proxy.validate_listener(i, &provider)?;
             proxy.validate_backend(i)?;
+
+            if let Some(name) = &proxy.name {
+                if name.is_empty() || name.len() > 255 {
+                    return Err(anyhow!(
+                        "proxy[{i}].name must be between 1 and 255 characters"
+                    ));
+                }
+                if !name.chars().all(|c| c.is_ascii_graphic() && c != ':') {
+                    return Err(anyhow!(
+                        "proxy[{i}].name '{name}' may only contain printable ASCII characters other than ':'"
+                    ));
+                }
+                if let Some(first) = self.proxies[..i]
+                    .iter()
+                    .position(|other| other.name.as_ref() == Some(name))
+                {
+                    return Err(anyhow!(
+                        "proxy[{i}].name '{name}' is already used by proxy[{first}]"
+                    ));
+                }
+            }
+
+            // Routes are identified by their listener when the configuration is reloaded
+            let bind_address = &proxy.listener.bind_address;
+            if let Some(first) = self.proxies[..i]
+                .iter()
+                .position(|other| &other.listener.bind_address == bind_address)
+            {
+                return Err(anyhow!(
+                    "proxy[{i}].listener.bind_address {bind_address} is already used by proxy[{first}]"
+                ));
+            }
         }
 
         Ok(())
@@ -119,167 +690,1112 @@ impl Config {
 }
 
 impl Proxy {
-    fn validate_listener(&self, index: usize) -> Result<()> {
+    fn validate_listener(&self, index: usize, provider: &CryptoProvider) -> Result<()> {
         let prefix = format!("proxy[{index}].listener");
 
-        // Validate server certificate and key sources
-        self.validate_cert_source(&self.listener.server_cert, &format!("{prefix}.server_cert"))?;
-        self.validate_cert_source(&self.listener.server_key, &format!("{prefix}.server_key"))?;
+        // Validate server certificate and key sources, unless an issuer provides them
+        let issuer = match (&self.listener.acme, &self.listener.vault) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "{prefix}.acme and {prefix}.vault are mutually exclusive"
+                ));
+            }
+            (Some(_), None) if provider.fips() => {
+                return Err(anyhow!(
+                    "{prefix}.acme is not available in FIPS mode, as ACME keys and requests are signed outside the FIPS module"
+                ));
+            }
+            (Some(acme), None) => {
+                self.validate_acme(acme, &format!("{prefix}.acme"))?;
+                Some("acme")
+            }
+            (None, Some(vault)) => {
+                self.validate_vault(vault, &format!("{prefix}.vault"))?;
+                Some("vault")
+            }
+            (None, None) => None,
+        };
+
+        if let Some(issuer) = issuer {
+            if self.listener.server_cert.is_some() || self.listener.server_key.is_some() {
+                return Err(anyhow!(
+                    "{prefix}.server_cert and {prefix}.server_key must not be set when {prefix}.{issuer} is configured"
+                ));
+            }
+        } else if (self.listener.is_unix() || self.backend.tls.is_some())
+            && self.listener.server_cert.is_none()
+            && self.listener.server_key.is_none()
+        {
+            // Plaintext-only listener, on a local socket or in front of a TLS backend;
+            // SSLRequests are declined
+            if self.listener.client_auth() != ClientAuth::None {
+                return Err(anyhow!(
+                    "{prefix}.client_auth requires server_cert and server_key"
+                ));
+            }
+        } else {
+            let server_cert = self.listener.server_cert.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "{prefix}.server_cert is required unless acme or vault is configured, \
+                     or the listener is a unix: socket or the backend uses TLS"
+                )
+            })?;
+            let server_key = self.listener.server_key.as_ref().ok_or_else(|| {
+                anyhow!("{prefix}.server_key is required unless acme or vault is configured")
+            })?;
+            self.validate_cert_source(server_cert, &format!("{prefix}.server_cert"))?;
+            self.validate_cert_source(server_key, &format!("{prefix}.server_key"))?;
+        }
+
+        if self.listener.require_https_for_keys {
+            // Tampering with any of these is as bad as reading the key: a forged client CA
+            // admits any client
+            let locations = [
+                (
+                    "server_key",
+                    self.listener.server_key.as_ref().map(CertSource::location),
+                ),
+                (
+                    "server_cert",
+                    self.listener.server_cert.as_ref().map(CertSource::location),
+                ),
+                (
+                    "client_ca",
+                    self.listener.client_ca.as_ref().map(CertSource::location),
+                ),
+                (
+                    "vault.address",
+                    self.listener
+                        .vault
+                        .as_ref()
+                        .map(|vault| vault.address.as_str()),
+                ),
+            ];
+            for (name, location) in locations {
+                if let Some(location) = location
+                    && location.starts_with("http://")
+                {
+                    return Err(anyhow!(
+                        "{prefix}.{name} must use https:// when require_https_for_keys is true: {location}"
+                    ));
+                }
+            }
+        }
 
-        // If mTLS is enabled, client_ca must be present and valid
-        if self.listener.mtls {
-            let client_ca = self
+        if self.listener.mtls
+            && self
                 .listener
-                .client_ca
-                .as_ref()
-                .ok_or_else(|| anyhow!("{}.client_ca is required when mtls is true", prefix))?;
+                .client_auth
+                .is_some_and(|client_auth| client_auth != ClientAuth::Required)
+        {
+            return Err(anyhow!(
+                "{}.mtls = true conflicts with {}.client_auth; remove mtls",
+                prefix,
+                prefix
+            ));
+        }
+        if self.listener.allow_plaintext && self.listener.client_auth() != ClientAuth::Optional {
+            return Err(anyhow!(
+                "{prefix}.allow_plaintext requires client_auth = \"optional\""
+            ));
+        }
+
+        crate::tls::server_config_builder(&self.listener, provider.clone())
+            .map_err(|e| anyhow!("{}: {}", prefix, e))?;
+
+        self.validate_session_tickets(&prefix, provider)?;
+
+        if self.listener.proxy_protocol.is_some() && self.listener.proxy_protocol_trusted.is_empty()
+        {
+            return Err(anyhow!(
+                "{prefix}.proxy_protocol_trusted must list the upstream CIDRs allowed to send PROXY headers"
+            ));
+        }
+
+        self.validate_unix_listener(&prefix)?;
+        if self.listener.max_connections == Some(0)
+            || self.listener.max_connections_per_client == Some(0)
+        {
+            return Err(anyhow!(
+                "{prefix}.max_connections and max_connections_per_client must be greater than 0"
+            ));
+        }
+        for (name, limit) in [
+            ("rate_limit", &self.listener.rate_limit),
+            ("client_rate_limit", &self.listener.client_rate_limit),
+        ] {
+            if let Some(limit) = limit
+                && !(limit.rate.is_finite() && limit.rate > 0.0 && limit.burst != Some(0))
+            {
+                return Err(anyhow!(
+                    "{prefix}.{name}.rate and burst must be greater than 0"
+                ));
+            }
+            if let Some(limit) = limit
+                && limit.rate < MIN_RATE
+            {
+                return Err(anyhow!("{prefix}.{name}.rate must be at least {MIN_RATE}"));
+            }
+        }
+        if self.listener.startup_timeout.is_zero() || self.listener.tls_handshake_timeout.is_zero()
+        {
+            return Err(anyhow!(
+                "{prefix}.startup_timeout and tls_handshake_timeout must be greater than 0"
+            ));
+        }
+        if let Some(ban) = &self.listener.ban
+            && (ban.max_failures == 0 || ban.find_time.is_zero() || ban.ban_time.is_zero())
+        {
+            return Err(anyhow!(
+                "{prefix}.ban.max_failures, find_time and ban_time must be greater than 0"
+            ));
+        }
+
+        // If client certificates are verified, client_ca must be present and valid
+        if self.listener.client_auth() != ClientAuth::None {
+            let client_ca = self.listener.client_ca.as_ref().ok_or_else(|| {
+                if self.listener.client_auth.is_some() {
+                    anyhow!(
+                        "{}.client_ca is required when client_auth is optional or required",
+                        prefix
+                    )
+                } else {
+                    anyhow!("{}.client_ca is required when mtls is true", prefix)
+                }
+            })?;
             self.validate_cert_source(client_ca, &format!("{}.client_ca", prefix))?;
         }
 
         Ok(())
     }
 
-    fn validate_cert_source(&self, cert_source: &str, field_name: &str) -> Result<()> {
-        if Listener::is_url(cert_source) {
-            // Validate URL format
-            if !cert_source.starts_with("https://") && !cert_source.starts_with("http://") {
+    fn validate_unix_listener(&self, prefix: &str) -> Result<()> {
+        let listener = &self.listener;
+        if !listener.is_unix() {
+            if listener.socket_mode.is_some()
+                || listener.socket_owner.is_some()
+                || listener.socket_group.is_some()
+            {
                 return Err(anyhow!(
-                    "Invalid URL format for {}: {}",
-                    field_name,
-                    cert_source
+                    "{prefix}.socket_mode, socket_owner and socket_group require a unix: bind_address"
                 ));
             }
-        } else {
-            // File path - check if file exists
-            self.check_file_exists(cert_source, field_name)?;
+            return Ok(());
+        }
+
+        crate::backend::BackendAddress::parse(&listener.bind_address)
+            .map_err(|e| anyhow!("{prefix}.bind_address: {e}"))?;
+        listener
+            .socket_mode()
+            .map_err(|e| anyhow!("{prefix}.socket_mode: {e}"))?;
+        if listener.proxy_protocol.is_some() {
+            return Err(anyhow!(
+                "{prefix}.proxy_protocol is not supported on a unix: listener"
+            ));
+        }
+        if listener.max_connections_per_client.is_some()
+            || listener.client_rate_limit.is_some()
+            || listener.ban.is_some()
+            || !listener.allow.is_empty()
+            || !listener.deny.is_empty()
+        {
+            return Err(anyhow!(
+                "{prefix}.max_connections_per_client, client_rate_limit, ban, allow and deny are not supported on a unix: listener"
+            ));
         }
         Ok(())
     }
 
-    fn validate_backend(&self, index: usize) -> Result<()> {
-        let _prefix = format!("proxy[{index}].backend");
-        // No validation needed for plaintext-only backends
+    fn validate_session_tickets(&self, prefix: &str, provider: &CryptoProvider) -> Result<()> {
+        let listener = &self.listener;
+        if !listener.session_tickets {
+            if listener.ticket_key_file.is_some() {
+                return Err(anyhow!(
+                    "{prefix}.ticket_key_file requires session_tickets = true"
+                ));
+            }
+            return Ok(());
+        }
+
+        if provider.fips() {
+            return Err(anyhow!(
+                "{prefix}.session_tickets is not available in FIPS mode, as tickets are not encrypted by the FIPS module"
+            ));
+        }
+        if listener.ticket_rotation_interval.as_secs() < 60 {
+            return Err(anyhow!(
+                "{prefix}.ticket_rotation_interval must be at least 1 minute"
+            ));
+        }
+        if let Some(ticket_key_file) = &listener.ticket_key_file {
+            let field_name = format!("{prefix}.ticket_key_file");
+            self.check_file_exists(ticket_key_file, &field_name)?;
+            let len = fs::metadata(ticket_key_file)?.len();
+            if len < crate::tls::MIN_TICKET_SECRET_LEN as u64 {
+                return Err(anyhow!(
+                    "{} must hold at least {} bytes of secret material",
+                    field_name,
+                    crate::tls::MIN_TICKET_SECRET_LEN
+                ));
+            }
+        }
         Ok(())
     }
 
-    fn check_file_exists(&self, path: &str, field_name: &str) -> Result<()> {
-        if !Path::new(path).exists() {
-            return Err(anyhow!("File not found for {}: {}", field_name, path));
+    fn validate_acme(&self, acme: &Acme, prefix: &str) -> Result<()> {
+        if !Listener::is_url(&acme.directory_url) {
+            return Err(anyhow!(
+                "Invalid URL format for {}.directory_url: {}",
+                prefix,
+                acme.directory_url
+            ));
+        }
+        if acme.domains.is_empty() {
+            return Err(anyhow!(
+                "{}.domains must contain at least one domain",
+                prefix
+            ));
+        }
+        if acme.challenge == AcmeChallenge::Dns01 && acme.dns_hook.is_none() {
+            return Err(anyhow!(
+                "{}.dns_hook is required when challenge is dns-01",
+                prefix
+            ));
         }
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::NamedTempFile;
+    fn validate_vault(&self, vault: &Vault, prefix: &str) -> Result<()> {
+        if !Listener::is_url(&vault.address) {
+            return Err(anyhow!(
+                "Invalid URL format for {}.address: {}",
+                prefix,
+                vault.address
+            ));
+        }
+        if !(vault.renew_fraction > 0.0 && vault.renew_fraction < 1.0) {
+            return Err(anyhow!(
+                "{}.renew_fraction must be between 0 and 1, got {}",
+                prefix,
+                vault.renew_fraction
+            ));
+        }
+        if let Some(ca_cert) = &vault.ca_cert {
+            self.check_file_exists(ca_cert, &format!("{prefix}.ca_cert"))?;
+        }
 
-    fn create_temp_file(content: &str) -> NamedTempFile {
-        let temp_file = NamedTempFile::new().expect("Failed to create temporary file");
-        fs::write(temp_file.path(), content).expect("Failed to write to temporary file");
-        temp_file
+        match &vault.auth {
+            VaultAuth::Token { token, token_file } => {
+                if token.is_none() && token_file.is_none() && std::env::var("VAULT_TOKEN").is_err()
+                {
+                    return Err(anyhow!(
+                        "{}.auth requires token, token_file or the VAULT_TOKEN environment variable",
+                        prefix
+                    ));
+                }
+            }
+            VaultAuth::AppRole { .. } | VaultAuth::Kubernetes { .. } => {}
+        }
+        Ok(())
     }
 
-    fn create_dummy_cert_files() -> (NamedTempFile, NamedTempFile, NamedTempFile, NamedTempFile) {
-        let server_cert = create_temp_file("dummy server cert");
-        let server_key = create_temp_file("dummy server key");
-        let client_ca = create_temp_file("dummy client ca");
-        let backend_ca = create_temp_file("dummy backend ca");
-        (server_cert, server_key, client_ca, backend_ca)
+    fn validate_cert_source(&self, cert_source: &CertSource, field_name: &str) -> Result<()> {
+        let location = cert_source.location();
+        if Listener::is_url(location) {
+            // Validate URL format
+            if !location.starts_with("https://") && !location.starts_with("http://") {
+                return Err(anyhow!(
+                    "Invalid URL format for {}: {}",
+                    field_name,
+                    location
+                ));
+            }
+        } else if let CertSource::Remote(_) = cert_source {
+            return Err(anyhow!(
+                "{}.url must be an http:// or https:// URL: {}",
+                field_name,
+                location
+            ));
+        } else {
+            // File path - check if file exists
+            self.check_file_exists(location, field_name)?;
+        }
+
+        if let CertSource::Remote(remote) = cert_source {
+            self.validate_remote_source(remote, field_name)?;
+        }
+        Ok(())
     }
 
-    #[test]
-    fn test_load_full_config() {
-        let (server_cert, server_key, client_ca, _backend_ca) = create_dummy_cert_files();
+    fn validate_remote_source(&self, remote: &RemoteSource, field_name: &str) -> Result<()> {
+        if remote.has_tls_options() && !remote.url.starts_with("https://") {
+            return Err(anyhow!(
+                "{}: ca_cert, pin_sha256 and client_cert require an https:// URL",
+                field_name
+            ));
+        }
+        if let Some(ca_cert) = &remote.ca_cert {
+            self.check_file_exists(ca_cert, &format!("{field_name}.ca_cert"))?;
+        }
+        for (pin, digest) in remote.pin_sha256.iter().zip(remote.pins()) {
+            let valid = STANDARD
+                .decode(digest)
+                .is_ok_and(|digest| digest.len() == 32);
+            if !valid {
+                return Err(anyhow!(
+                    "{}.pin_sha256 entries must be base64 encoded SHA-256 digests: {}",
+                    field_name,
+                    pin
+                ));
+            }
+        }
+        match (&remote.client_cert, &remote.client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                self.check_file_exists(client_cert, &format!("{field_name}.client_cert"))?;
+                self.check_file_exists(client_key, &format!("{field_name}.client_key"))?;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(anyhow!(
+                    "{}.client_cert and {}.client_key must be set together",
+                    field_name,
+                    field_name
+                ));
+            }
+        }
+        if remote.bearer_token_env.is_some() && remote.bearer_token_file.is_some() {
+            return Err(anyhow!(
+                "{}.bearer_token_env and {}.bearer_token_file are mutually exclusive",
+                field_name,
+                field_name
+            ));
+        }
+        if let Some(basic_auth) = &remote.basic_auth
+            && basic_auth.password_env.is_some() == basic_auth.password_file.is_some()
+        {
+            return Err(anyhow!(
+                "{}.basic_auth requires exactly one of password_env or password_file",
+                field_name
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_backend(&self, index: usize) -> Result<()> {
+        let prefix = format!("proxy[{index}].backend");
+        crate::backend::BackendAddress::parse(&self.backend.address)
+            .map_err(|e| anyhow!("{prefix}.address: {e}"))?;
+        if self.backend.proxy_protocol_ssl_tlv && !self.backend.send_proxy_protocol {
+            return Err(anyhow!(
+                "{prefix}.proxy_protocol_ssl_tlv requires send_proxy_protocol = true"
+            ));
+        }
+        if let Some(parameters) = &self.backend.startup_parameters {
+            if let Some(template) = &parameters.application_name {
+                crate::startup::validate_template(template)
+                    .map_err(|e| anyhow!("{prefix}.startup_parameters.application_name: {e}"))?;
+            }
+            for (name, template) in &parameters.options {
+                if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '=') {
+                    return Err(anyhow!(
+                        "{prefix}.startup_parameters.options has invalid setting name '{name}'"
+                    ));
+                }
+                crate::startup::validate_template(template)
+                    .map_err(|e| anyhow!("{prefix}.startup_parameters.options.{name}: {e}"))?;
+            }
+        }
+        if let Some(tls) = &self.backend.tls {
+            self.validate_backend_tls(tls, &format!("{prefix}.tls"))?;
+        }
+        Ok(())
+    }
+
+    fn validate_backend_tls(&self, tls: &BackendTls, prefix: &str) -> Result<()> {
+        if let Some(ca_cert) = &tls.ca_cert {
+            self.check_file_exists(ca_cert, &format!("{prefix}.ca_cert"))?;
+        }
+        match (&tls.client_cert, &tls.client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                self.check_file_exists(client_cert, &format!("{prefix}.client_cert"))?;
+                self.check_file_exists(client_key, &format!("{prefix}.client_key"))?;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(anyhow!(
+                    "{prefix}.client_cert and {prefix}.client_key must be set together"
+                ));
+            }
+        }
+        crate::backend::server_name(&self.backend.address, tls)
+            .map_err(|e| anyhow!("{prefix}.server_name: {e}"))?;
+        Ok(())
+    }
+
+    fn check_file_exists(&self, path: &str, field_name: &str) -> Result<()> {
+        if !Path::new(path).exists() {
+            return Err(anyhow!("File not found for {}: {}", field_name, path));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::NamedTempFile;
+
+    fn create_temp_file(content: &str) -> NamedTempFile {
+        let temp_file = NamedTempFile::new().expect("Failed to create temporary file");
+        fs::write(temp_file.path(), content).expect("Failed to write to temporary file");
+        temp_file
+    }
+
+    fn create_dummy_cert_files() -> (NamedTempFile, NamedTempFile, NamedTempFile, NamedTempFile) {
+        let server_cert = create_temp_file("dummy server cert");
+        let server_key = create_temp_file("dummy server key");
+        let client_ca = create_temp_file("dummy client ca");
+        let backend_ca = create_temp_file("dummy backend ca");
+        (server_cert, server_key, client_ca, backend_ca)
+    }
+
+    #[test]
+    fn test_load_full_config() {
+        let (server_cert, server_key, client_ca, _backend_ca) = create_dummy_cert_files();
+
+        let config_content = format!(
+            r#"
+log_level = "debug"
+
+[[proxy]]
+  [proxy.listener]
+  bind_address = "0.0.0.0:6432"
+  server_cert = "{}"
+  server_key = "{}"
+  mtls = true
+  client_ca = "{}"
+
+  [proxy.backend]
+  address = "db.example.com:5432"
+
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6433"
+  server_cert = "{}"
+  server_key = "{}"
+  mtls = false
+
+  [proxy.backend]
+  address = "10.0.1.50:5432"
+"#,
+            server_cert.path().display(),
+            server_key.path().display(),
+            client_ca.path().display(),
+            server_cert.path().display(),
+            server_key.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.proxies.len(), 2);
+
+        // First proxy
+        let proxy1 = &config.proxies[0];
+        assert_eq!(proxy1.listener.bind_address, "0.0.0.0:6432");
+        assert!(proxy1.listener.mtls);
+        assert!(proxy1.listener.client_ca.is_some());
+        assert_eq!(proxy1.backend.address, "db.example.com:5432");
+
+        // Second proxy
+        let proxy2 = &config.proxies[1];
+        assert_eq!(proxy2.listener.bind_address, "127.0.0.1:6433");
+        assert!(!proxy2.listener.mtls);
+        assert!(proxy2.listener.client_ca.is_none());
+        assert_eq!(proxy2.backend.address, "10.0.1.50:5432");
+    }
+
+    #[test]
+    fn test_load_minimal_config() {
+        let (server_cert, server_key, _, _backend_ca) = create_dummy_cert_files();
+
+        let config_content = format!(
+            r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_cert = "{}"
+  server_key = "{}"
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#,
+            server_cert.path().display(),
+            server_key.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+
+        // Check defaults
+        assert_eq!(config.log_level, "info"); // default
+        assert_eq!(config.proxies.len(), 1);
+
+        let proxy = &config.proxies[0];
+        assert!(!proxy.listener.mtls); // default false
+    }
+
+    #[test]
+    fn test_validation_mtls_without_client_ca() {
+        let (server_cert, server_key, _, _) = create_dummy_cert_files();
+
+        let config_content = format!(
+            r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_cert = "{}"
+  server_key = "{}"
+  mtls = true
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#,
+            server_cert.path().display(),
+            server_key.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("client_ca is required when mtls is true")
+        );
+    }
+
+    #[test]
+    fn test_validation_unknown_cipher_suite() {
+        let (server_cert, server_key, _, _) = create_dummy_cert_files();
+
+        let config_content = format!(
+            r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_cert = "{}"
+  server_key = "{}"
+  min_tls_version = "1.3"
+  cipher_suites = ["TLS13_AES_128_GCM_SHA256", "TLS_RSA_WITH_RC4_128_SHA"]
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#,
+            server_cert.path().display(),
+            server_key.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("proxy[0].listener: Unknown cipher suite 'TLS_RSA_WITH_RC4_128_SHA'")
+        );
+    }
+
+    #[test]
+    fn test_validation_fips_requires_validated_provider() {
+        let (server_cert, server_key, _, _) = create_dummy_cert_files();
+
+        let config_content = format!(
+            r#"
+fips = true
+
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_cert = "{}"
+  server_key = "{}"
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#,
+            server_cert.path().display(),
+            server_key.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("the ring crypto provider is not FIPS-validated")
+        );
+    }
+
+    #[test]
+    fn test_validation_duplicate_bind_address() {
+        let config_content = r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "unix:/tmp/.s.PGSQL.6432"
+
+  [proxy.backend]
+  address = "localhost:5432"
+
+[[proxy]]
+  [proxy.listener]
+  bind_address = "unix:/tmp/.s.PGSQL.6432"
+
+  [proxy.backend]
+  address = "localhost:5433"
+"#;
+
+        let config_file = create_temp_file(config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.unwrap_err().to_string().contains(
+            "proxy[1].listener.bind_address unix:/tmp/.s.PGSQL.6432 is already used by proxy[0]"
+        ));
+    }
+
+    #[test]
+    fn test_validation_route_name() {
+        let load = |first: &str, second: &str| {
+            let config_content = format!(
+                r#"
+[[proxy]]
+  name = "{first}"
+  [proxy.listener]
+  bind_address = "unix:/tmp/.s.PGSQL.6432"
+  [proxy.backend]
+  address = "localhost:5432"
+
+[[proxy]]
+  name = "{second}"
+  [proxy.listener]
+  bind_address = "unix:/tmp/.s.PGSQL.6433"
+  [proxy.backend]
+  address = "localhost:5433"
+"#
+            );
+            let config_file = create_temp_file(&config_content);
+            Config::load(config_file.path().to_str().unwrap())
+        };
+
+        assert!(load("public", "internal").is_ok());
+        assert!(
+            load("public", "public")
+                .unwrap_err()
+                .to_string()
+                .contains("proxy[1].name 'public' is already used by proxy[0]")
+        );
+        assert!(
+            load("public", "in:ternal")
+                .unwrap_err()
+                .to_string()
+                .contains("proxy[1].name 'in:ternal' may only contain")
+        );
+    }
+
+    #[test]
+    fn test_admin_and_route_failure() {
+        let load = |global: &str| {
+            let config_content = format!(
+                r#"
+{global}
+
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  [proxy.backend]
+  address = "localhost:5432"
+  [proxy.backend.tls]
+  mode = "require"
+"#
+            );
+            let config_file = create_temp_file(&config_content);
+            Config::load(config_file.path().to_str().unwrap())
+        };
+
+        let config = load("").unwrap();
+        assert_eq!(config.route_failure, RouteFailurePolicy::Restart);
+        assert_eq!(config.admin, None);
+
+        let config =
+            load("route_failure = \"exit\"\n[admin]\nbind_address = \"127.0.0.1:9090\"").unwrap();
+        assert_eq!(config.route_failure, RouteFailurePolicy::Exit);
+        assert_eq!(config.admin.unwrap().bind_address, "127.0.0.1:9090");
+
+        let error = load("[admin]\nbind_address = \"localhost:9090\"").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("admin.bind_address must be an IP address and port")
+        );
+        let error = load("[admin]\nbind_address = \"127.0.0.1:6432\"").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("admin.bind_address '127.0.0.1:6432' is already used by proxy[0]")
+        );
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let load = |global: &str, listener: &str| {
+            let config_content = format!(
+                r#"
+{global}
+
+[[proxy]]
+  [proxy.listener]
+  {listener}
+  [proxy.backend]
+  address = "localhost:5432"
+  [proxy.backend.tls]
+  mode = "require"
+"#
+            );
+            let config_file = create_temp_file(&config_content);
+            Config::load(config_file.path().to_str().unwrap())
+        };
+
+        let config = load(
+            "max_connections = 500",
+            "bind_address = \"127.0.0.1:6432\"\n  max_connections = 100\n  \
+             max_connections_per_client = 10\n  connection_queue_timeout = \"5s\"",
+        )
+        .unwrap();
+        assert_eq!(config.max_connections, Some(500));
+        let listener = &config.proxies[0].listener;
+        assert_eq!(listener.max_connections, Some(100));
+        assert_eq!(listener.max_connections_per_client, Some(10));
+        assert_eq!(listener.connection_queue_timeout, Duration::from_secs(5));
+
+        let config = load("", "bind_address = \"127.0.0.1:6432\"").unwrap();
+        assert_eq!(config.max_connections, None);
+        assert_eq!(
+            config.proxies[0].listener.connection_queue_timeout,
+            Duration::ZERO
+        );
+
+        let error = load("max_connections = 0", "bind_address = \"127.0.0.1:6432\"").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("max_connections must be greater than 0")
+        );
+        let error = load(
+            "",
+            "bind_address = \"127.0.0.1:6432\"\n  max_connections_per_client = 0",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains(
+            "proxy[0].listener.max_connections and max_connections_per_client must be greater than 0"
+        ));
+        let error = load(
+            "",
+            "bind_address = \"unix:/tmp/.s.PGSQL.6432\"\n  max_connections_per_client = 1",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains(
+            "proxy[0].listener.max_connections_per_client, client_rate_limit, ban, allow and deny are not supported on a unix: listener"
+        ));
+    }
+
+    #[test]
+    fn test_rate_limits_and_bans() {
+        let load = |listener: &str| {
+            let config_content = format!(
+                r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  {listener}
+  [proxy.backend]
+  address = "localhost:5432"
+  [proxy.backend.tls]
+  mode = "require"
+"#
+            );
+            let config_file = create_temp_file(&config_content);
+            Config::load(config_file.path().to_str().unwrap())
+        };
+
+        let config = load(
+            "[proxy.listener.rate_limit]\n  rate = 2.5\n  \
+             [proxy.listener.client_rate_limit]\n  rate = 1\n  burst = 5\n  \
+             [proxy.listener.ban]\n  max_failures = 5\n  ban_time = \"1h\"",
+        )
+        .unwrap();
+        let listener = &config.proxies[0].listener;
+        assert_eq!(listener.rate_limit.as_ref().unwrap().burst(), 3);
+        assert_eq!(listener.client_rate_limit.as_ref().unwrap().burst(), 5);
+        let ban = listener.ban.as_ref().unwrap();
+        assert_eq!(ban.find_time, Duration::from_secs(600));
+        assert_eq!(ban.ban_time, Duration::from_secs(3600));
+
+        let error = load("[proxy.listener.rate_limit]\n  rate = 0").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("proxy[0].listener.rate_limit.rate and burst must be greater than 0")
+        );
+        let error = load("[proxy.listener.rate_limit]\n  rate = 1e-20").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("proxy[0].listener.rate_limit.rate must be at least 0.001")
+        );
+        let error = load("[proxy.listener.ban]\n  max_failures = 0").unwrap_err();
+        assert!(error.to_string().contains(
+            "proxy[0].listener.ban.max_failures, find_time and ban_time must be greater than 0"
+        ));
+    }
+
+    #[test]
+    fn test_access_lists() {
+        let listener = |settings: &str| -> Listener {
+            toml::from_str(&format!("bind_address = \"[::]:6432\"\n{settings}")).unwrap()
+        };
+        let ip = |address: &str| address.parse::<IpAddr>().unwrap();
+
+        let open = listener("");
+        assert!(open.is_allowed(ip("192.0.2.1")));
+        assert!(open.is_allowed(ip("2001:db8::1")));
+
+        let lists = r#"allow = ["10.0.0.0/8", "2001:db8::/32"]
+deny = ["10.1.0.0/16", "2001:db8:bad::/48"]"#;
+        let deny_first = listener(lists);
+        assert!(deny_first.is_allowed(ip("10.2.3.4")));
+        assert!(!deny_first.is_allowed(ip("10.1.2.3")));
+        assert!(!deny_first.is_allowed(ip("192.0.2.1")));
+        assert!(deny_first.is_allowed(ip("2001:db8::1")));
+        assert!(!deny_first.is_allowed(ip("2001:db8:bad::1")));
+        // IPv4 clients of a dual-stack listener match IPv4 networks
+        assert!(deny_first.is_allowed(ip("::ffff:10.2.3.4")));
+        assert!(!deny_first.is_allowed(ip("::ffff:10.1.2.3")));
+
+        let allow_first = listener(&format!("{lists}\naccess_order = \"allow-first\""));
+        assert!(allow_first.is_allowed(ip("10.1.2.3")));
+        assert!(allow_first.is_allowed(ip("192.0.2.1")));
+
+        // Exceptions to a denied network, with everyone else allowed
+        let exceptions = listener(
+            r#"deny = ["10.0.0.0/8"]
+allow = ["10.1.2.3/32"]
+access_order = "allow-first""#,
+        );
+        assert!(exceptions.is_allowed(ip("10.1.2.3")));
+        assert!(!exceptions.is_allowed(ip("10.1.2.4")));
+        assert!(exceptions.is_allowed(ip("192.0.2.1")));
+        let deny_only = listener(r#"deny = ["10.0.0.0/8"]"#);
+        assert!(deny_only.is_allowed(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn test_connection_timeouts() {
+        let listener = |settings: &str| {
+            toml::from_str::<Listener>(&format!("bind_address = \"127.0.0.1:6432\"\n{settings}"))
+        };
+
+        let defaults = listener("").unwrap();
+        assert_eq!(defaults.startup_timeout, Duration::from_secs(30));
+        assert_eq!(defaults.tls_handshake_timeout, Duration::from_secs(30));
+        assert!(defaults.idle_timeout.is_zero());
+        assert!(defaults.max_connection_lifetime.is_zero());
+
+        let configured = listener(
+            "startup_timeout = \"5s\"\ntls_handshake_timeout = \"10s\"\n\
+             idle_timeout = \"15min\"\nmax_connection_lifetime = \"1d\"",
+        )
+        .unwrap();
+        assert_eq!(configured.startup_timeout, Duration::from_secs(5));
+        assert_eq!(configured.tls_handshake_timeout, Duration::from_secs(10));
+        assert_eq!(configured.idle_timeout, Duration::from_secs(900));
+        assert_eq!(
+            configured.max_connection_lifetime,
+            Duration::from_secs(86400)
+        );
+
+        let config_content = r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "unix:/tmp/.s.PGSQL.6432"
+  startup_timeout = "0s"
+  [proxy.backend]
+  address = "localhost:5432"
+"#;
+        let config_file = create_temp_file(config_content);
+        let error = Config::load(config_file.path().to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains(
+            "proxy[0].listener.startup_timeout and tls_handshake_timeout must be greater than 0"
+        ));
+    }
+
+    #[test]
+    fn test_validation_short_ticket_key_file() {
+        let (server_cert, server_key, _, _) = create_dummy_cert_files();
+        let ticket_key = create_temp_file("too short");
+
+        let config_content = format!(
+            r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_cert = "{}"
+  server_key = "{}"
+  session_tickets = true
+  ticket_rotation_interval = "1h"
+  ticket_key_file = "{}"
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#,
+            server_cert.path().display(),
+            server_key.path().display(),
+            ticket_key.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("ticket_key_file must hold at least 32 bytes")
+        );
+    }
+
+    #[test]
+    fn test_proxy_protocol_config() {
+        let (server_cert, server_key, _, _) = create_dummy_cert_files();
 
         let config_content = format!(
             r#"
-log_level = "debug"
-
-[[proxy]]
-  [proxy.listener]
-  bind_address = "0.0.0.0:6432"
-  server_cert = "{}"
-  server_key = "{}"
-  mtls = true
-  client_ca = "{}"
-
-  [proxy.backend]
-  address = "db.example.com:5432"
-
 [[proxy]]
   [proxy.listener]
-  bind_address = "127.0.0.1:6433"
+  bind_address = "127.0.0.1:6432"
   server_cert = "{}"
   server_key = "{}"
-  mtls = false
+  proxy_protocol = "v2"
+  proxy_protocol_trusted = ["10.0.0.0/8", "2001:db8::/32"]
 
   [proxy.backend]
-  address = "10.0.1.50:5432"
+  address = "localhost:5432"
 "#,
             server_cert.path().display(),
             server_key.path().display(),
-            client_ca.path().display(),
-            server_cert.path().display(),
-            server_key.path().display(),
         );
 
         let config_file = create_temp_file(&config_content);
         let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+        let listener = &config.proxies[0].listener;
+        assert_eq!(listener.proxy_protocol, Some(ProxyProtocolMode::V2));
+        assert_eq!(listener.proxy_protocol_trusted.len(), 2);
 
-        assert_eq!(config.log_level, "debug");
-        assert_eq!(config.proxies.len(), 2);
-
-        // First proxy
-        let proxy1 = &config.proxies[0];
-        assert_eq!(proxy1.listener.bind_address, "0.0.0.0:6432");
-        assert!(proxy1.listener.mtls);
-        assert!(proxy1.listener.client_ca.is_some());
-        assert_eq!(proxy1.backend.address, "db.example.com:5432");
-
-        // Second proxy
-        let proxy2 = &config.proxies[1];
-        assert_eq!(proxy2.listener.bind_address, "127.0.0.1:6433");
-        assert!(!proxy2.listener.mtls);
-        assert!(proxy2.listener.client_ca.is_none());
-        assert_eq!(proxy2.backend.address, "10.0.1.50:5432");
+        let untrusted = config_content.replace(
+            r#"proxy_protocol_trusted = ["10.0.0.0/8", "2001:db8::/32"]"#,
+            "",
+        );
+        let config_file = create_temp_file(&untrusted);
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("proxy_protocol_trusted must list the upstream CIDRs")
+        );
     }
 
     #[test]
-    fn test_load_minimal_config() {
-        let (server_cert, server_key, _, _backend_ca) = create_dummy_cert_files();
+    fn test_client_auth_modes() {
+        let (server_cert, server_key, client_ca, _) = create_dummy_cert_files();
 
         let config_content = format!(
             r#"
 [[proxy]]
   [proxy.listener]
   bind_address = "127.0.0.1:6432"
-  server_cert = "{}"
-  server_key = "{}"
+  server_cert = "{cert}"
+  server_key = "{key}"
+  client_auth = "optional"
+  client_ca = "{ca}"
 
   [proxy.backend]
   address = "localhost:5432"
+
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6433"
+  server_cert = "{cert}"
+  server_key = "{key}"
+  mtls = true
+  client_ca = "{ca}"
+
+  [proxy.backend]
+  address = "localhost:5433"
 "#,
-            server_cert.path().display(),
-            server_key.path().display(),
+            cert = server_cert.path().display(),
+            key = server_key.path().display(),
+            ca = client_ca.path().display(),
         );
 
         let config_file = create_temp_file(&config_content);
         let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
 
-        // Check defaults
-        assert_eq!(config.log_level, "info"); // default
-        assert_eq!(config.proxies.len(), 1);
+        assert_eq!(
+            config.proxies[0].listener.client_auth(),
+            ClientAuth::Optional
+        );
+        assert_eq!(
+            config.proxies[1].listener.client_auth(),
+            ClientAuth::Required
+        );
+        assert!(!config.proxies[0].listener.accepts_plaintext());
+        assert!(!config.proxies[1].listener.accepts_plaintext());
+
+        // Plaintext clients are an explicit choice, and only in optional mode
+        let allowing = config_content.replacen(
+            "client_auth = \"optional\"",
+            "client_auth = \"optional\"\n  allow_plaintext = true",
+            1,
+        );
+        let config_file = create_temp_file(&allowing);
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+        assert!(config.proxies[0].listener.accepts_plaintext());
 
-        let proxy = &config.proxies[0];
-        assert!(!proxy.listener.mtls); // default false
+        let required =
+            config_content.replacen("mtls = true", "mtls = true\n  allow_plaintext = true", 1);
+        let config_file = create_temp_file(&required);
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("proxy[1].listener.allow_plaintext requires client_auth = \"optional\"")
+        );
     }
 
     #[test]
-    fn test_validation_mtls_without_client_ca() {
-        let (server_cert, server_key, _, _) = create_dummy_cert_files();
+    fn test_validation_mtls_conflicts_with_client_auth() {
+        let (server_cert, server_key, client_ca, _) = create_dummy_cert_files();
 
         let config_content = format!(
             r#"
@@ -289,12 +1805,15 @@ log_level = "debug"
   server_cert = "{}"
   server_key = "{}"
   mtls = true
+  client_auth = "optional"
+  client_ca = "{}"
 
   [proxy.backend]
   address = "localhost:5432"
 "#,
             server_cert.path().display(),
             server_key.path().display(),
+            client_ca.path().display(),
         );
 
         let config_file = create_temp_file(&config_content);
@@ -305,7 +1824,7 @@ log_level = "debug"
             result
                 .unwrap_err()
                 .to_string()
-                .contains("client_ca is required when mtls is true")
+                .contains("mtls = true conflicts with")
         );
     }
 
@@ -386,6 +1905,53 @@ log_level = "info"
         assert_eq!(proxy.listener.cert_refresh_interval.as_secs(), 12 * 3600);
     }
 
+    #[test]
+    fn test_startup_parameters_config() {
+        let (server_cert, server_key, _, _) = create_dummy_cert_files();
+
+        let config_content = format!(
+            r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_cert = "{}"
+  server_key = "{}"
+
+  [proxy.backend]
+  address = "localhost:5432"
+
+  [proxy.backend.startup_parameters]
+  application_name = "{{application_name}} via {{client_ip}}"
+  options = {{ "pgtls.client_cn" = "{{client_cert_cn}}" }}
+"#,
+            server_cert.path().display(),
+            server_key.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+        let parameters = config.proxies[0]
+            .backend
+            .startup_parameters
+            .as_ref()
+            .unwrap();
+        assert_eq!(
+            parameters.application_name.as_deref(),
+            Some("{application_name} via {client_ip}")
+        );
+        assert_eq!(parameters.options["pgtls.client_cn"], "{client_cert_cn}");
+
+        let unknown = config_content.replace("{client_cert_cn}", "{client_cert}");
+        let config_file = create_temp_file(&unknown);
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Unknown placeholder '{client_cert}'")
+        );
+    }
+
     #[test]
     fn test_url_certificate_with_refresh() {
         // Test URL configuration format - this will fail validation but should parse
@@ -411,14 +1977,258 @@ log_level = "info"
 
         let proxy = &config.proxies[0];
         assert_eq!(proxy.listener.cert_refresh_interval.as_secs(), 6 * 3600);
-        assert!(Listener::is_url(&proxy.listener.server_cert));
-        assert!(Listener::is_url(&proxy.listener.server_key));
+        assert!(proxy.listener.server_cert.as_ref().unwrap().is_url());
+        assert!(proxy.listener.server_key.as_ref().unwrap().is_url());
         assert!(
             !proxy
                 .listener
                 .client_ca
                 .as_ref()
-                .is_some_and(|ca| Listener::is_url(ca))
+                .is_some_and(|ca| ca.is_url())
+        );
+    }
+
+    #[test]
+    fn test_acme_config() {
+        let state_dir = tempfile::tempdir().unwrap();
+
+        let config_content = format!(
+            r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "0.0.0.0:443"
+
+  [proxy.listener.acme]
+  directory_url = "https://acme-v02.api.letsencrypt.org/directory"
+  domains = ["db.example.com"]
+  contact = ["mailto:ops@example.com"]
+  state_dir = "{}"
+  renew_before = "14d"
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#,
+            state_dir.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+
+        let listener = &config.proxies[0].listener;
+        assert!(listener.server_cert.is_none());
+        let acme = listener.acme.as_ref().unwrap();
+        assert_eq!(acme.domains, vec!["db.example.com"]);
+        assert_eq!(acme.challenge, AcmeChallenge::TlsAlpn01); // default
+        assert_eq!(acme.renew_before.as_secs(), 14 * 24 * 3600);
+    }
+
+    #[test]
+    fn test_validation_acme_dns01_without_hook() {
+        let config_content = r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "0.0.0.0:6432"
+
+  [proxy.listener.acme]
+  directory_url = "https://ca.internal/acme/acme/directory"
+  domains = ["db.example.com"]
+  state_dir = "/var/lib/pgtls"
+  challenge = "dns-01"
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#;
+
+        let config_file = create_temp_file(config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("dns_hook is required when challenge is dns-01")
+        );
+    }
+
+    #[test]
+    fn test_validation_missing_server_cert() {
+        let config_content = r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#;
+
+        let config_file = create_temp_file(config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("server_cert is required unless acme or vault is configured")
+        );
+    }
+
+    #[test]
+    fn test_vault_config() {
+        let config_content = r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "0.0.0.0:6432"
+
+  [proxy.listener.vault]
+  address = "https://vault.example.com:8200"
+  role = "pgtls"
+  common_name = "db.example.com"
+  ttl = "72h"
+
+  [proxy.listener.vault.auth]
+  method = "approle"
+  role_id = "db-proxy"
+  secret_id_file = "/etc/pgtls/secret-id"
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#;
+
+        let config_file = create_temp_file(config_content);
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+
+        let vault = config.proxies[0].listener.vault.as_ref().unwrap();
+        assert_eq!(vault.pki_mount, "pki"); // default
+        assert_eq!(vault.renew_fraction, 0.66); // default
+        match &vault.auth {
+            VaultAuth::AppRole { role_id, mount, .. } => {
+                assert_eq!(role_id, "db-proxy");
+                assert_eq!(mount, "approle");
+            }
+            other => panic!("Expected AppRole auth, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_remote_source_options() {
+        let (_, _, client_ca, _) = create_dummy_cert_files();
+
+        let config_content = format!(
+            r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_cert = "https://certs.example.com/server.pem"
+  require_https_for_keys = true
+
+  [proxy.listener.server_key]
+  url = "https://certs.example.com/server.key"
+  bearer_token_env = "CERT_TOKEN"
+  ca_cert = "{}"
+  pin_sha256 = [
+    "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+    "sha256//LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=",
+  ]
+  headers = {{ X-Tenant = "db" }}
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#,
+            client_ca.path().display(),
+        );
+
+        let config_file = create_temp_file(&config_content);
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+
+        let listener = &config.proxies[0].listener;
+        assert!(matches!(
+            listener.server_cert,
+            Some(CertSource::Location(_))
+        ));
+        match listener.server_key.as_ref().unwrap() {
+            CertSource::Remote(remote) => {
+                assert_eq!(remote.url, "https://certs.example.com/server.key");
+                assert_eq!(remote.bearer_token_env.as_deref(), Some("CERT_TOKEN"));
+                assert_eq!(remote.headers["X-Tenant"], "db");
+                assert!(remote.has_tls_options());
+                assert_eq!(
+                    remote.pins().collect::<Vec<_>>(),
+                    [
+                        "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+                        "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+                    ]
+                );
+            }
+            other => panic!("Expected remote source, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validation_plain_http_key_refused() {
+        let config_content = r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_cert = "http://certs.example.com/server.pem"
+  server_key = "http://certs.example.com/server.key"
+  require_https_for_keys = true
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#;
+
+        let config_file = create_temp_file(config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("server_key must use https:// when require_https_for_keys is true")
+        );
+
+        // Every other certificate URL is covered too
+        let config_content = config_content
+            .replace("http://certs.example.com/server", "https://certs.example.com/server")
+            .replace(
+                "require_https_for_keys = true",
+                "require_https_for_keys = true\n  mtls = true\n  client_ca = \"http://certs.example.com/ca.pem\"",
+            );
+        let config_file = create_temp_file(&config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.unwrap_err().to_string().contains(
+            "proxy[0].listener.client_ca must use https:// when require_https_for_keys is true"
+        ));
+    }
+
+    #[test]
+    fn test_validation_invalid_pin() {
+        let config_content = r#"
+[[proxy]]
+  [proxy.listener]
+  bind_address = "127.0.0.1:6432"
+  server_key = "https://certs.example.com/server.key"
+
+  [proxy.listener.server_cert]
+  url = "https://certs.example.com/server.pem"
+  pin_sha256 = ["not-a-digest"]
+
+  [proxy.backend]
+  address = "localhost:5432"
+"#;
+
+        let config_file = create_temp_file(config_content);
+        let result = Config::load(config_file.path().to_str().unwrap());
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("pin_sha256 entries must be base64 encoded SHA-256 digests")
         );
     }
 }
@@ -0,0 +1,267 @@
+use crate::config;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Open client connections across all routes, so that shutdown can wait for them to finish
+#[derive(Clone, Default)]
+pub struct Connections {
+    tracker: TaskTracker,
+    close: CancellationToken,
+    notify_clients: Arc<AtomicBool>,
+    limiter: Arc<Limiter>,
+}
+
+/// Connections admitted under the connection limits, by route and client address
+#[derive(Default)]
+struct Limiter {
+    counts: Mutex<Counts>,
+    released: Notify,
+}
+
+#[derive(Default)]
+struct Counts {
+    max_connections: Option<usize>,
+    total: usize,
+    by_route: HashMap<String, usize>,
+    by_client: HashMap<(String, IpAddr), usize>,
+}
+
+/// The connection limit that turned a client away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// The global `max_connections`
+    Global,
+    /// The listener's `max_connections`
+    Route,
+    /// The listener's `max_connections_per_client`
+    Client,
+}
+
+impl LimitExceeded {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::Global => "global",
+            Self::Route => "route",
+            Self::Client => "client",
+        }
+    }
+}
+
+/// Counts a connection against the limits until dropped
+pub struct ConnectionPermit {
+    limiter: Arc<Limiter>,
+    route: String,
+    client: Option<IpAddr>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        {
+            let mut counts = self.limiter.counts.lock().unwrap();
+            counts.total -= 1;
+            decrement(&mut counts.by_route, self.route.clone());
+            if let Some(client) = self.client {
+                decrement(&mut counts.by_client, (self.route.clone(), client));
+            }
+        }
+        self.limiter.released.notify_waiters();
+    }
+}
+
+fn decrement<K: Eq + std::hash::Hash>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+impl Limiter {
+    fn try_acquire(
+        self: &Arc<Self>,
+        listener: &config::Listener,
+        client: Option<IpAddr>,
+    ) -> Result<ConnectionPermit, LimitExceeded> {
+        let mut counts = self.counts.lock().unwrap();
+        let route = &listener.bind_address;
+        if counts
+            .max_connections
+            .is_some_and(|max| counts.total >= max)
+        {
+            return Err(LimitExceeded::Global);
+        }
+        let route_count = counts.by_route.get(route).copied().unwrap_or(0);
+        if listener
+            .max_connections
+            .is_some_and(|max| route_count >= max)
+        {
+            return Err(LimitExceeded::Route);
+        }
+        if let (Some(max), Some(client)) = (listener.max_connections_per_client, client) {
+            let key = (route.clone(), client);
+            if counts.by_client.get(&key).copied().unwrap_or(0) >= max {
+                return Err(LimitExceeded::Client);
+            }
+            *counts.by_client.entry(key).or_default() += 1;
+        }
+        counts.total += 1;
+        *counts.by_route.entry(route.clone()).or_default() += 1;
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            route: route.clone(),
+            client,
+        })
+    }
+}
+
+impl Connections {
+    pub fn spawn<F>(&self, connection: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tracker.spawn(connection);
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    /// Resolve once open connections must close, with whether clients should be told why
+    pub async fn closing(&self) -> bool {
+        self.close.cancelled().await;
+        self.notify_clients.load(Ordering::Acquire)
+    }
+
+    /// Wait until every open connection has finished
+    pub async fn drained(&self) {
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+
+    /// Ask open connections to close
+    pub fn close_all(&self, notify_clients: bool) {
+        self.notify_clients.store(notify_clients, Ordering::Release);
+        self.close.cancel();
+    }
+
+    /// Limit admitted connections across all routes; `None` removes the limit
+    pub fn set_max_connections(&self, max_connections: Option<usize>) {
+        self.limiter.counts.lock().unwrap().max_connections = max_connections;
+        // A raised limit may let queued connections in
+        self.limiter.released.notify_waiters();
+    }
+
+    /// Admit a connection to the route of `listener` from `client`, waiting up to the
+    /// listener's `connection_queue_timeout` for room under the connection limits
+    pub async fn admit(
+        &self,
+        listener: &config::Listener,
+        client: Option<IpAddr>,
+    ) -> Result<ConnectionPermit, LimitExceeded> {
+        let deadline = Instant::now() + listener.connection_queue_timeout;
+        loop {
+            let released = self.limiter.released.notified();
+            tokio::pin!(released);
+            // Registered before checking, so a release in between is not missed
+            released.as_mut().enable();
+            let exceeded = match self.limiter.try_acquire(listener, client) {
+                Ok(permit) => return Ok(permit),
+                Err(exceeded) => exceeded,
+            };
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(exceeded);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_drained_waits_for_connections() {
+        let connections = Connections::default();
+        let connection = connections.clone();
+        connections.spawn(async move {
+            assert!(connection.closing().await);
+        });
+        assert_eq!(connections.len(), 1);
+
+        let drained = tokio::time::timeout(Duration::from_millis(50), connections.drained());
+        assert!(drained.await.is_err());
+
+        connections.close_all(true);
+        connections.drained().await;
+        assert_eq!(connections.len(), 0);
+    }
+
+    fn listener(max_connections: Option<usize>, max_per_client: Option<usize>) -> config::Listener {
+        let mut listener = config::Listener::for_tests("127.0.0.1:6432", "");
+        listener.max_connections = max_connections;
+        listener.max_connections_per_client = max_per_client;
+        listener
+    }
+
+    #[tokio::test]
+    async fn test_admit_enforces_limits() {
+        let connections = Connections::default();
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let listener = listener(Some(3), Some(2));
+        let _a = connections.admit(&listener, Some(first)).await.unwrap();
+        let b = connections.admit(&listener, Some(first)).await.unwrap();
+        assert_eq!(
+            connections.admit(&listener, Some(first)).await.err(),
+            Some(LimitExceeded::Client)
+        );
+        let _c = connections.admit(&listener, Some(second)).await.unwrap();
+        assert_eq!(
+            connections.admit(&listener, None).await.err(),
+            Some(LimitExceeded::Route)
+        );
+
+        // Closing a connection makes room again
+        drop(b);
+        let _d = connections.admit(&listener, Some(first)).await.unwrap();
+
+        connections.set_max_connections(Some(3));
+        let other = self::listener(None, None);
+        assert_eq!(
+            connections.admit(&other, None).await.err(),
+            Some(LimitExceeded::Global)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admit_queues_until_timeout() {
+        let connections = Connections::default();
+        let mut listener = listener(Some(1), None);
+        listener.connection_queue_timeout = Duration::from_millis(50);
+
+        let permit = connections.admit(&listener, None).await.unwrap();
+        assert_eq!(
+            connections.admit(&listener, None).await.err(),
+            Some(LimitExceeded::Route)
+        );
+
+        let queued = tokio::spawn({
+            let connections = connections.clone();
+            let listener = listener.clone();
+            async move { connections.admit(&listener, None).await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(permit);
+        assert!(queued.await.unwrap());
+    }
+}
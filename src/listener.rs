@@ -0,0 +1,137 @@
+use crate::config::Listener;
+use anyhow::{Context, Result, anyhow};
+use nix::unistd::{Gid, Group, Uid, User, chown};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Bind a Unix socket listener, replacing a stale socket left behind by a previous run
+pub fn bind_unix(path: &Path, listener_config: &Listener) -> Result<UnixListener> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind Unix socket {}", path.display()))?;
+    set_socket_permissions(path, listener_config)?;
+    Ok(listener)
+}
+
+/// Apply `socket_mode`, `socket_owner` and `socket_group` to a bound socket
+pub fn set_socket_permissions(path: &Path, listener_config: &Listener) -> Result<()> {
+    if let Some(mode) = listener_config.socket_mode()? {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set mode of {}", path.display()))?;
+    }
+
+    let owner = listener_config
+        .socket_owner
+        .as_deref()
+        .map(resolve_user)
+        .transpose()?;
+    let group = listener_config
+        .socket_group
+        .as_deref()
+        .map(resolve_group)
+        .transpose()?;
+    if owner.is_some() || group.is_some() {
+        chown(path, owner, group)
+            .with_context(|| format!("Failed to change ownership of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!(
+            "{} exists and is not a Unix socket",
+            path.display()
+        ));
+    }
+
+    // A socket nobody accepts on was left behind by a process that did not exit cleanly
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(anyhow!(
+            "Unix socket {} is in use by another process",
+            path.display()
+        )),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            tracing::info!("Removing stale Unix socket {}", path.display());
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => Err(anyhow!(
+            "Failed to check existing Unix socket {}: {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+fn resolve_user(user: &str) -> Result<Uid> {
+    if let Ok(uid) = user.parse::<u32>() {
+        return Ok(Uid::from_raw(uid));
+    }
+    User::from_name(user)?
+        .map(|user| user.uid)
+        .ok_or_else(|| anyhow!("Unknown socket_owner '{}'", user))
+}
+
+fn resolve_group(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(group)?
+        .map(|group| group.gid)
+        .ok_or_else(|| anyhow!("Unknown socket_group '{}'", group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(path: &Path) -> Listener {
+        let content = format!(
+            "bind_address = \"unix:{}\"\nsocket_mode = \"0600\"\nsocket_group = \"{}\"",
+            path.display(),
+            nix::unistd::getegid()
+        );
+        toml::from_str(&content).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_sets_mode_and_replaces_stale_socket() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(".s.PGSQL.6432");
+        let listener_config = listener(&path);
+
+        // Leave a socket file behind without anything accepting on it
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let _listener = bind_unix(&path, &listener_config).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A live socket is not replaced
+        assert!(
+            bind_unix(&path, &listener_config)
+                .unwrap_err()
+                .to_string()
+                .contains("in use")
+        );
+    }
+
+    #[test]
+    fn test_bind_unix_refuses_regular_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("not-a-socket");
+        fs::write(&path, "data").unwrap();
+
+        assert!(bind_unix(&path, &listener(&path)).is_err());
+        assert!(path.exists());
+    }
+}
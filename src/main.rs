@@ -1,14 +1,38 @@
 use anyhow::Result;
 use clap::Parser;
 use std::process;
+use std::time::Duration;
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
+mod acme;
+mod admin;
+mod backend;
 mod cert_manager;
 mod config;
+mod connections;
+#[cfg(unix)]
+mod listener;
+mod metrics;
+#[cfg(test)]
+mod mock_http;
 mod protocol;
 mod proxy;
+mod proxy_protocol;
+mod rate_limit;
+mod routes;
+mod startup;
+mod systemd;
+mod tls;
+mod upgrade;
+mod vault;
 
 use config::Config;
+use connections::Connections;
+use routes::RouteSet;
+use upgrade::ListenerSockets;
+
+/// How long closed connections get to send their final message before the process exits
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -31,6 +55,11 @@ async fn main() -> Result<()> {
         .with(filter)
         .init();
 
+    if let Err(e) = tls::install_crypto_provider(&config) {
+        tracing::error!("Failed to set up crypto provider: {}", e);
+        process::exit(1);
+    }
+
     if config.proxies.is_empty() {
         tracing::error!("No proxy configurations found in {}", args.config);
         process::exit(1);
@@ -41,19 +70,176 @@ async fn main() -> Result<()> {
         config.proxies.len()
     );
 
-    // Start all proxy tasks
-    let mut tasks = Vec::new();
+    let sockets = ListenerSockets::default();
+    #[cfg(unix)]
+    let handoff = match upgrade::inherit(&sockets).await {
+        Ok(handoff) => handoff,
+        Err(e) => {
+            tracing::error!(
+                "Failed to take over listeners from the previous process: {:#}",
+                e
+            );
+            process::exit(1);
+        }
+    };
+    #[cfg(unix)]
+    let upgraded = handoff.is_some();
+    #[cfg(not(unix))]
+    let upgraded = false;
+    #[cfg(unix)]
+    if !upgraded && let Err(e) = systemd::inherit_sockets(&config.proxies, &sockets) {
+        tracing::error!("Failed to use sockets from systemd: {:#}", e);
+        process::exit(1);
+    }
+
+    // Bound before the handoff completes, which closes inherited sockets nothing has claimed
+    let admin_listener = match &config.admin {
+        Some(admin) => match admin::bind(admin, &sockets).await {
+            Ok(listener) => Some((listener, admin.bind_address.clone())),
+            Err(e) => {
+                tracing::error!(
+                    "Failed to bind admin endpoint {}: {:#}",
+                    admin.bind_address,
+                    e
+                );
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let connections = Connections::default();
+    connections.set_max_connections(config.max_connections);
+    let mut routes = RouteSet::start(
+        config.proxies.clone(),
+        config.route_failure,
+        connections.clone(),
+        sockets.clone(),
+    );
+    let admin_shutdown = tokio_util::sync::CancellationToken::new();
+    if let Some((listener, bind_address)) = admin_listener {
+        let state = admin::AdminState {
+            health: routes.health(),
+            connections: connections.clone(),
+            rate_limiter: routes.rate_limiter(),
+        };
+        tokio::spawn(admin::serve(
+            listener,
+            bind_address,
+            state,
+            sockets.clone(),
+            admin_shutdown.clone(),
+        ));
+    }
+    {
+        let health = routes.health();
+        #[cfg(unix)]
+        let sockets = sockets.clone();
+        tokio::spawn(async move {
+            // Routes that fail to start are retried in the background and do not hold this up
+            health.settled().await;
+            systemd::ready(upgraded);
+            #[cfg(unix)]
+            if let Some(handoff) = handoff {
+                handoff.complete(&sockets).await;
+            }
+        });
+    }
+    let mut running = config;
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut reload = ControlSignal::hangup()?;
+    let mut upgrade = ControlSignal::user_defined2()?;
+    let mut watchdog = systemd::Watchdog::new(upgraded);
+    let mut handed_off = false;
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = reload.recv() => {
+                tracing::info!("Received SIGHUP, reloading {}", args.config);
+                systemd::reloading();
+                match Config::load(&args.config) {
+                    Ok(config) => {
+                        if config.crypto_provider != running.crypto_provider
+                            || config.fips != running.fips
+                            || config.admin != running.admin
+                        {
+                            tracing::warn!(
+                                "crypto_provider, fips and admin changes take effect after a restart"
+                            );
+                        }
+                        connections.set_max_connections(config.max_connections);
+                        routes.apply(config.proxies.clone(), config.route_failure);
+                        tracing::info!("Configuration reloaded with {} route(s)", routes.len());
+                        running = config;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to reload configuration, keeping the running one: {:#}", e);
+                    }
+                }
+                systemd::ready(false);
+            }
+            _ = upgrade.recv() => {
+                tracing::info!("Received SIGUSR2, handing listeners to a new process");
+                #[cfg(unix)]
+                match upgrade::hand_off(&sockets).await {
+                    Ok(()) => {
+                        tracing::info!("New process is accepting connections, draining this one");
+                        handed_off = true;
+                        break;
+                    }
+                    Err(e) => tracing::error!("Upgrade failed, continuing to serve: {:#}", e),
+                }
+            }
+            _ = watchdog.tick() => {}
+            // Routes only stop on their own when failures are fatal or the task panicked
+            (bind_address, result) = routes.wait_any() => {
+                match result {
+                    Ok(Ok(())) => tracing::info!("Proxy for {} completed, shutting down.", bind_address),
+                    Ok(Err(e)) => tracing::error!("Proxy for {} failed: {:#}, shutting down.", bind_address, e),
+                    Err(e) => tracing::error!("Proxy task for {} failed: {}, shutting down.", bind_address, e),
+                }
+                break;
+            }
+        }
+    }
 
-    for proxy_config in config.proxies {
+    // The new process reports to systemd after an upgrade
+    if !handed_off {
+        systemd::stopping();
+    }
+    // Stop accepting, then give open sessions until the timeout to finish
+    admin_shutdown.cancel();
+    routes.stop().await;
+    let open = connections.len();
+    if open > 0 {
         tracing::info!(
-            "Starting proxy for listener: {}",
-            proxy_config.listener.bind_address
+            "Waiting up to {:?} for {} open connection(s) to finish",
+            running.shutdown_timeout,
+            open
+        );
+    }
+    if tokio::time::timeout(running.shutdown_timeout, connections.drained())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Shutdown timeout reached, closing {} open connection(s)",
+            connections.len()
         );
-        let task = tokio::spawn(proxy::run_proxy(proxy_config));
-        tasks.push(task);
+        connections.close_all(running.shutdown_notify_clients);
+        // Brief grace period for the admin_shutdown errors to be written
+        let _ = tokio::time::timeout(CLOSE_GRACE_PERIOD, connections.drained()).await;
     }
 
-    // Wait for shutdown signal
+    tracing::info!("Shutdown complete.");
+    Ok(())
+}
+
+/// Resolve on Ctrl+C or, on Unix, SIGTERM
+async fn shutdown_signal() {
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             tracing::info!("Received Ctrl+C, shutting down.");
@@ -64,17 +250,7 @@ async fn main() -> Result<()> {
                 tracing::error!("Error setting up signal handler: {}", e);
             }
         }
-        // If any proxy task completes (likely due to error), shut down
-        result = futures::future::select_all(tasks.iter_mut()) => {
-            match result.0 {
-                Ok(_) => tracing::info!("Proxy task completed, shutting down."),
-                Err(e) => tracing::error!("Proxy task failed: {}, shutting down.", e),
-            }
-        }
     }
-
-    tracing::info!("Shutdown complete.");
-    Ok(())
 }
 
 #[cfg(unix)]
@@ -89,3 +265,44 @@ async fn setup_sigterm_handler() -> Result<(), std::io::Error> {
 async fn setup_sigterm_handler() -> Result<(), std::io::Error> {
     futures::future::pending::<Result<(), std::io::Error>>().await
 }
+
+/// Unix signal used to trigger a reload or an upgrade; never fires on other platforms
+#[cfg(unix)]
+struct ControlSignal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl ControlSignal {
+    fn hangup() -> Result<Self> {
+        Self::new(tokio::signal::unix::SignalKind::hangup())
+    }
+
+    fn user_defined2() -> Result<Self> {
+        Self::new(tokio::signal::unix::SignalKind::user_defined2())
+    }
+
+    fn new(kind: tokio::signal::unix::SignalKind) -> Result<Self> {
+        Ok(Self(tokio::signal::unix::signal(kind)?))
+    }
+
+    async fn recv(&mut self) {
+        self.0.recv().await;
+    }
+}
+
+#[cfg(not(unix))]
+struct ControlSignal;
+
+#[cfg(not(unix))]
+impl ControlSignal {
+    fn hangup() -> Result<Self> {
+        Ok(Self)
+    }
+
+    fn user_defined2() -> Result<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        futures::future::pending::<()>().await
+    }
+}
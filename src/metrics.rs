@@ -0,0 +1,153 @@
+use crate::connections::Connections;
+use crate::routes::{Health, RouteState};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+
+/// A counter exported on the admin endpoint's `/metrics`
+pub struct Counter {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+pub const CONNECTIONS_ACCEPTED: Counter = Counter {
+    name: "pgtls_connections_accepted_total",
+    help: "Client connections admitted to a route",
+};
+
+pub const CONNECTIONS_REJECTED: Counter = Counter {
+    name: "pgtls_connections_rejected_total",
+    help: "Client connections rejected by a connection limit",
+};
+
+pub const CONNECTIONS_REFUSED: Counter = Counter {
+    name: "pgtls_connections_refused_total",
+    help: "New connections refused by a rate limit or ban",
+};
+
+pub const CLIENTS_BANNED: Counter = Counter {
+    name: "pgtls_clients_banned_total",
+    help: "Client addresses banned after repeated failed TLS handshakes",
+};
+
+pub const CONNECTION_TIMEOUTS: Counter = Counter {
+    name: "pgtls_connection_timeouts_total",
+    help: "Connections closed by a startup, TLS handshake, idle or lifetime timeout",
+};
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Help text and values by labels of one counter
+type Values = (&'static str, BTreeMap<Labels, u64>);
+
+/// Counters by name
+static COUNTERS: LazyLock<Mutex<BTreeMap<&'static str, Values>>> = LazyLock::new(Default::default);
+
+pub fn increment(counter: &Counter, labels: &[(&'static str, &str)]) {
+    let labels = labels
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect();
+    let mut counters = COUNTERS.lock().unwrap();
+    let (_, values) = counters
+        .entry(counter.name)
+        .or_insert_with(|| (counter.help, BTreeMap::new()));
+    *values.entry(labels).or_default() += 1;
+}
+
+/// All metrics in the Prometheus text exposition format
+pub fn render(connections: &Connections, health: &Health) -> String {
+    let mut output = String::new();
+    write_header(
+        &mut output,
+        "pgtls_connections_open",
+        "Client connections currently open",
+        "gauge",
+    );
+    let _ = writeln!(output, "pgtls_connections_open {}", connections.len());
+
+    let routes = health.routes();
+    write_header(
+        &mut output,
+        "pgtls_route_up",
+        "Whether the route is accepting connections",
+        "gauge",
+    );
+    for route in &routes {
+        let up = u8::from(route.state == RouteState::Running);
+        let _ = writeln!(
+            output,
+            "pgtls_route_up{} {}",
+            format_labels(&[("route", route.bind_address.clone())]),
+            up
+        );
+    }
+    write_header(
+        &mut output,
+        "pgtls_route_restarts_total",
+        "Restarts of the route after a failure",
+        "counter",
+    );
+    for route in &routes {
+        let _ = writeln!(
+            output,
+            "pgtls_route_restarts_total{} {}",
+            format_labels(&[("route", route.bind_address.clone())]),
+            route.restarts
+        );
+    }
+
+    for (name, (help, values)) in COUNTERS.lock().unwrap().iter() {
+        write_header(&mut output, name, help, "counter");
+        for (labels, value) in values {
+            let _ = writeln!(output, "{name}{} {value}", format_labels(labels));
+        }
+    }
+    output
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {kind}");
+}
+
+fn format_labels(labels: &[(&'static str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters() {
+        increment(
+            &CONNECTIONS_REJECTED,
+            &[("route", "unix:/tmp/\"a\""), ("limit", "route")],
+        );
+        increment(
+            &CONNECTIONS_REJECTED,
+            &[("route", "unix:/tmp/\"a\""), ("limit", "route")],
+        );
+
+        let output = render(&Connections::default(), &Health::default());
+        assert!(output.contains("pgtls_connections_open 0\n"));
+        assert!(output.contains("# TYPE pgtls_connections_rejected_total counter\n"));
+        assert!(output.contains(
+            "pgtls_connections_rejected_total{route=\"unix:/tmp/\\\"a\\\"\",limit=\"route\"} 2\n"
+        ));
+    }
+}
@@ -1,21 +1,33 @@
-use anyhow::Result;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 const SSL_REQUEST_CODE: u32 = 80877103;
+const CANCEL_REQUEST_CODE: u32 = 80877102;
+/// SSLRequest packet: length 8 followed by SSL_REQUEST_CODE
+pub const SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xD2, 0x16, 0x2F];
+/// Largest startup packet accepted, matching PostgreSQL's MAX_STARTUP_PACKET_LENGTH
+const MAX_STARTUP_LENGTH: usize = 10000;
+/// First byte of a TLS handshake record, sent by clients that skip the SSLRequest
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
 
 #[derive(Debug, PartialEq)]
 pub enum RequestType<'a> {
     Ssl,
-    Startup(&'a [u8]), // The initial bytes, to be replayed
+    Startup(&'a [u8]),   // The initial bytes, to be replayed
+    DirectTls(&'a [u8]), // The start of a TLS ClientHello, to be replayed
 }
 
 pub async fn parse_request<'a>(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     buffer: &'a mut [u8; 8],
 ) -> Result<RequestType<'a>> {
     stream.read_exact(buffer).await?;
 
+    // A startup packet length never starts with 0x16, so this can only be a raw TLS handshake
+    if buffer[0] == TLS_HANDSHAKE_RECORD {
+        return Ok(RequestType::DirectTls(buffer));
+    }
+
     let length = u32::from_be_bytes(buffer[0..4].try_into()?);
     if length != 8 {
         return Ok(RequestType::Startup(buffer));
@@ -29,6 +41,213 @@ pub async fn parse_request<'a>(
     }
 }
 
+/// Whether the first 8 bytes of a startup packet begin a CancelRequest
+pub fn is_cancel_request(header: &[u8]) -> bool {
+    header.len() >= 8
+        && header[0..4] == 16u32.to_be_bytes()
+        && header[4..8] == CANCEL_REQUEST_CODE.to_be_bytes()
+}
+
+/// Read the rest of a startup packet whose first 8 bytes have already been consumed
+pub async fn read_startup_packet(
+    stream: &mut (impl AsyncRead + Unpin),
+    header: &[u8],
+) -> Result<Vec<u8>> {
+    let length = u32::from_be_bytes(header[0..4].try_into()?) as usize;
+    if !(8..=MAX_STARTUP_LENGTH).contains(&length) {
+        return Err(anyhow!("Invalid startup packet length {}", length));
+    }
+    let mut packet = header[..8].to_vec();
+    packet.resize(length, 0);
+    stream.read_exact(&mut packet[8..]).await?;
+    Ok(packet)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StartupMessage {
+    pub protocol_version: u32,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl StartupMessage {
+    /// Parse a protocol 3.x StartupMessage, or `None` for other packets such as CancelRequest
+    pub fn parse(packet: &[u8]) -> Result<Option<Self>> {
+        if packet.len() < 8 {
+            return Err(anyhow!("Startup packet too short"));
+        }
+        let protocol_version = u32::from_be_bytes(packet[4..8].try_into()?);
+        if protocol_version >> 16 != 3 {
+            return Ok(None);
+        }
+
+        let mut fields = packet[8..].split(|&b| b == 0);
+        let mut parameters = Vec::new();
+        loop {
+            let name = fields
+                .next()
+                .ok_or_else(|| anyhow!("Startup packet is not terminated"))?;
+            if name.is_empty() {
+                break;
+            }
+            let value = fields
+                .next()
+                .ok_or_else(|| anyhow!("Startup parameter has no value"))?;
+            parameters.push((
+                String::from_utf8(name.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ));
+        }
+        Ok(Some(Self {
+            protocol_version,
+            parameters,
+        }))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set(&mut self, name: &str, value: String) {
+        match self.parameters.iter_mut().find(|(key, _)| key == name) {
+            Some((_, existing)) => *existing = value,
+            None => self.parameters.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = vec![0u8; 4];
+        packet.extend_from_slice(&self.protocol_version.to_be_bytes());
+        for (name, value) in &self.parameters {
+            packet.extend_from_slice(name.as_bytes());
+            packet.push(0);
+            packet.extend_from_slice(value.as_bytes());
+            packet.push(0);
+        }
+        packet.push(0);
+        let length = packet.len() as u32;
+        packet[0..4].copy_from_slice(&length.to_be_bytes());
+        packet
+    }
+}
+
+/// Server message sent whenever it is ready for a new query
+pub const READY_FOR_QUERY: u8 = b'Z';
+/// Client messages the server answers with exactly one ReadyForQuery: Query, Sync and
+/// FunctionCall
+pub const READY_FOR_QUERY_REQUESTS: &[u8] = b"QSF";
+
+/// SQLSTATE sent to clients closed by a proxy shutdown
+pub const ADMIN_SHUTDOWN: &str = "57P01";
+
+/// SQLSTATE sent to clients closed by `idle_timeout`
+pub const IDLE_SESSION_TIMEOUT: &str = "57P05";
+
+/// SQLSTATE sent to clients turned away by a connection limit
+pub const TOO_MANY_CONNECTIONS: &str = "53300";
+
+/// SQLSTATE sent to plaintext clients of a listener that checks client certificates
+pub const INVALID_AUTHORIZATION: &str = "28000";
+
+/// Encode a FATAL ErrorResponse message
+pub fn fatal_error(code: &str, message: &str) -> Vec<u8> {
+    let mut packet = vec![b'E', 0, 0, 0, 0];
+    for (field, value) in [
+        (b'S', "FATAL"),
+        (b'V', "FATAL"),
+        (b'C', code),
+        (b'M', message),
+    ] {
+        packet.push(field);
+        packet.extend_from_slice(value.as_bytes());
+        packet.push(0);
+    }
+    packet.push(0);
+    let length = (packet.len() - 1) as u32;
+    packet[1..5].copy_from_slice(&length.to_be_bytes());
+    packet
+}
+
+/// Follows the message framing of a byte stream after startup, so that the proxy can tell
+/// when it is safe to insert a message of its own and what the last message was
+#[derive(Debug, Default)]
+pub struct MessageBoundary {
+    header: [u8; 5],
+    header_len: usize,
+    remaining: usize,
+    /// First body byte of the message being read
+    first: Option<u8>,
+    /// Type and first body byte of the last complete message
+    last: Option<(u8, Option<u8>)>,
+    counted_types: &'static [u8],
+    counted: u64,
+}
+
+impl MessageBoundary {
+    /// Follow a stream, counting the messages of the given types
+    pub fn counting(types: &'static [u8]) -> Self {
+        Self {
+            counted_types: types,
+            ..Self::default()
+        }
+    }
+
+    /// Account for relayed bytes
+    pub fn advance(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len());
+                self.first.get_or_insert(data[0]);
+                self.remaining -= n;
+                data = &data[n..];
+                if self.remaining == 0 {
+                    self.last = Some((self.header[0], self.first));
+                }
+                continue;
+            }
+            let n = (5 - self.header_len).min(data.len());
+            self.header[self.header_len..self.header_len + n].copy_from_slice(&data[..n]);
+            self.header_len += n;
+            data = &data[n..];
+            if self.header_len == 5 {
+                // The length includes itself but not the type byte
+                let length = u32::from_be_bytes([
+                    self.header[1],
+                    self.header[2],
+                    self.header[3],
+                    self.header[4],
+                ]);
+                self.remaining = (length as usize).saturating_sub(4);
+                self.header_len = 0;
+                self.first = None;
+                if self.counted_types.contains(&self.header[0]) {
+                    self.counted += 1;
+                }
+                if self.remaining == 0 {
+                    self.last = Some((self.header[0], None));
+                }
+            }
+        }
+    }
+
+    /// Whether the bytes relayed so far end on a message boundary
+    pub fn at_boundary(&self) -> bool {
+        self.header_len == 0 && self.remaining == 0
+    }
+
+    /// Whether the server's last message was ReadyForQuery outside a transaction block
+    pub fn ready_for_query(&self) -> bool {
+        self.at_boundary() && self.last == Some((READY_FOR_QUERY, Some(b'I')))
+    }
+
+    /// Messages begun so far whose type was passed to `counting`
+    pub fn counted(&self) -> u64 {
+        self.counted
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -61,7 +280,7 @@ mod tests {
             RequestType::Startup(bytes) => {
                 assert_eq!(bytes, &startup_bytes);
             }
-            RequestType::Ssl => panic!("Expected Startup, got Ssl"),
+            other => panic!("Expected Startup, got {other:?}"),
         }
     }
 
@@ -79,10 +298,23 @@ mod tests {
             RequestType::Startup(bytes) => {
                 assert_eq!(bytes, &invalid_ssl_bytes);
             }
-            RequestType::Ssl => panic!("Expected Startup, got Ssl"),
+            other => panic!("Expected Startup, got {other:?}"),
         }
     }
 
+    #[tokio::test]
+    async fn test_parse_direct_tls_client_hello() {
+        // TLS record header (handshake, TLS 1.0 record version) followed by a ClientHello header
+        let client_hello_bytes = [0x16u8, 0x03, 0x01, 0x00, 0xf4, 0x01, 0x00, 0x00];
+        let mut mock_stream = Builder::new().read(&client_hello_bytes).build();
+        let mut buffer = [0u8; 8];
+
+        let result = parse_request_from_mock(&mut mock_stream, &mut buffer).await;
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), RequestType::DirectTls(&client_hello_bytes));
+    }
+
     #[tokio::test]
     async fn test_stream_eof() {
         // Only 4 bytes instead of 8
@@ -142,29 +374,114 @@ mod tests {
                 assert_eq!(u32::from_be_bytes(bytes[0..4].try_into().unwrap()), 68);
                 assert_eq!(u32::from_be_bytes(bytes[4..8].try_into().unwrap()), 196608); // 3.0 protocol
             }
-            RequestType::Ssl => panic!("Expected Startup, got Ssl"),
+            other => panic!("Expected Startup, got {other:?}"),
         }
     }
 
+    #[tokio::test]
+    async fn test_startup_message_round_trip() {
+        let message = StartupMessage {
+            protocol_version: 196608,
+            parameters: vec![
+                ("user".to_string(), "alice".to_string()),
+                ("database".to_string(), "app".to_string()),
+            ],
+        };
+        let packet = message.encode();
+
+        let mut mock_stream = Builder::new().read(&packet[8..]).build();
+        let read = read_startup_packet(&mut mock_stream, &packet[..8])
+            .await
+            .unwrap();
+        assert_eq!(read, packet);
+
+        let mut parsed = StartupMessage::parse(&read).unwrap().unwrap();
+        assert_eq!(parsed, message);
+        assert_eq!(parsed.get("user"), Some("alice"));
+
+        parsed.set("application_name", "psql".to_string());
+        parsed.set("user", "bob".to_string());
+        let reparsed = StartupMessage::parse(&parsed.encode()).unwrap().unwrap();
+        assert_eq!(reparsed.get("application_name"), Some("psql"));
+        assert_eq!(reparsed.get("user"), Some("bob"));
+    }
+
+    #[test]
+    fn test_startup_message_skips_cancel_request() {
+        let cancel = [
+            0u8, 0, 0, 16, 0x04, 0xD2, 0x16, 0x2E, 0, 0, 0, 1, 0, 0, 0, 2,
+        ];
+        assert_eq!(StartupMessage::parse(&cancel).unwrap(), None);
+        assert!(StartupMessage::parse(&[0u8, 0, 0, 9, 0, 3, 0, 0, b'x']).is_err());
+        assert!(is_cancel_request(&cancel[..8]));
+        assert!(!is_cancel_request(&[0u8, 0, 0, 16, 0, 3, 0, 0]));
+    }
+
     // Helper function that works with the mock streams from tokio-test
     async fn parse_request_from_mock<'a>(
         stream: &mut (impl AsyncRead + Unpin),
         buffer: &'a mut [u8; 8],
     ) -> Result<RequestType<'a>> {
-        use tokio::io::AsyncReadExt;
+        parse_request(stream, buffer).await
+    }
 
-        stream.read_exact(buffer).await?;
+    #[test]
+    fn test_fatal_error() {
+        let packet = fatal_error(ADMIN_SHUTDOWN, "shutting down");
+        assert_eq!(packet[0], b'E');
+        let length = u32::from_be_bytes(packet[1..5].try_into().unwrap()) as usize;
+        assert_eq!(length, packet.len() - 1);
+        assert_eq!(&packet[5..], b"SFATAL\0VFATAL\0C57P01\0Mshutting down\0\0");
+    }
 
-        let length = u32::from_be_bytes(buffer[0..4].try_into()?);
-        if length != 8 {
-            return Ok(RequestType::Startup(buffer));
-        }
+    #[test]
+    fn test_message_boundary() {
+        let mut boundary = MessageBoundary::default();
+        assert!(boundary.at_boundary());
+
+        // ReadyForQuery ('Z', length 5, status 'I') split across reads
+        boundary.advance(&[b'Z', 0, 0]);
+        assert!(!boundary.at_boundary());
+        boundary.advance(&[0, 5]);
+        assert!(!boundary.at_boundary());
+        boundary.advance(b"I");
+        assert!(boundary.at_boundary());
+
+        // Two messages in one read, the second one incomplete
+        let mut data = vec![b'Z', 0, 0, 0, 5, b'I'];
+        data.extend_from_slice(&[b'D', 0, 0, 0, 10, 0, 1]);
+        boundary.advance(&data);
+        assert!(!boundary.at_boundary());
+        boundary.advance(&[0, 0, 0]);
+        assert!(!boundary.at_boundary());
+        boundary.advance(&[0]);
+        assert!(boundary.at_boundary());
+    }
 
-        let code = u32::from_be_bytes(buffer[4..8].try_into()?);
-        if code == SSL_REQUEST_CODE {
-            Ok(RequestType::Ssl)
-        } else {
-            Ok(RequestType::Startup(buffer))
-        }
+    #[test]
+    fn test_message_boundary_tracks_ready_for_query() {
+        let mut boundary = MessageBoundary::counting(&[READY_FOR_QUERY]);
+        assert!(!boundary.ready_for_query());
+
+        boundary.advance(&[b'Z', 0, 0, 0, 5]);
+        assert!(!boundary.ready_for_query());
+        boundary.advance(b"I");
+        assert!(boundary.ready_for_query());
+        assert_eq!(boundary.counted(), 1);
+
+        // Inside a transaction block the session is not idle
+        boundary.advance(&[b'Z', 0, 0, 0, 5, b'T']);
+        assert!(!boundary.ready_for_query());
+
+        // Nor once a query has started returning results
+        boundary.advance(&[b'Z', 0, 0, 0, 5, b'I', b'T', 0, 0, 0, 6, 0, 0]);
+        assert!(!boundary.ready_for_query());
+        assert_eq!(boundary.counted(), 3);
+
+        // Empty messages complete with their header
+        let mut requests = MessageBoundary::counting(READY_FOR_QUERY_REQUESTS);
+        requests.advance(&[b'H', 0, 0, 0, 4, b'S', 0, 0, 0, 4]);
+        assert!(requests.at_boundary());
+        assert_eq!(requests.counted(), 1);
     }
 }
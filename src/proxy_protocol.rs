@@ -0,0 +1,382 @@
+use crate::config::ProxyProtocolMode;
+use anyhow::{Result, anyhow};
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+/// Signature opening every PROXY protocol v2 header
+pub const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+/// Maximum length of a v1 header, including the trailing CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+
+/// TLS details of the client connection, sent as a PP2_TYPE_SSL TLV
+#[derive(Debug, Clone)]
+pub struct SslInfo {
+    pub version: String,
+    /// Subject CN of the verified client certificate
+    pub common_name: Option<String>,
+}
+
+fn push_tlv(out: &mut Vec<u8>, kind: u8, value: &[u8]) -> Result<()> {
+    let len = u16::try_from(value.len())
+        .map_err(|_| anyhow!("PROXY header TLV of {} bytes is too long", value.len()))?;
+    out.push(kind);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(value);
+    Ok(())
+}
+
+/// Encode a v2 PROXY header for a proxied TCP connection. Without addresses, as for clients on
+/// a Unix socket, a LOCAL header tells the receiver to use the connection's own endpoints.
+pub fn encode_v2(
+    addresses: Option<(SocketAddr, SocketAddr)>,
+    ssl: Option<&SslInfo>,
+) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    let (command, family) = match addresses {
+        None => (0x20, 0x00), // Version 2, LOCAL command, unspecified family
+        Some((source, destination)) => {
+            let family = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    payload.extend_from_slice(&source_ip.octets());
+                    payload.extend_from_slice(&destination_ip.octets());
+                    0x11
+                }
+                // Both addresses must share a family, so mix IPv4 in as IPv4-mapped IPv6
+                (source_ip, destination_ip) => {
+                    let to_v6 = |ip: IpAddr| match ip {
+                        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                        IpAddr::V6(v6) => v6,
+                    };
+                    payload.extend_from_slice(&to_v6(source_ip).octets());
+                    payload.extend_from_slice(&to_v6(destination_ip).octets());
+                    0x21
+                }
+            };
+            payload.extend_from_slice(&source.port().to_be_bytes());
+            payload.extend_from_slice(&destination.port().to_be_bytes());
+            (0x21, family) // Version 2, PROXY command
+        }
+    };
+
+    if let Some(ssl) = ssl {
+        let mut client = PP2_CLIENT_SSL;
+        let mut value = Vec::new();
+        push_tlv(&mut value, PP2_SUBTYPE_SSL_VERSION, ssl.version.as_bytes())?;
+        if let Some(common_name) = &ssl.common_name {
+            client |= PP2_CLIENT_CERT_CONN;
+            push_tlv(&mut value, PP2_SUBTYPE_SSL_CN, common_name.as_bytes())?;
+        }
+        // client flags, then verify result (0 = verified, as unverified certificates are rejected)
+        let mut ssl_tlv = vec![client, 0, 0, 0, 0];
+        ssl_tlv.extend_from_slice(&value);
+        push_tlv(&mut payload, PP2_TYPE_SSL, &ssl_tlv)?;
+    }
+
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(command);
+    header.push(family);
+    let len = u16::try_from(payload.len())
+        .map_err(|_| anyhow!("PROXY header of {} bytes is too long", payload.len()))?;
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&payload);
+    Ok(header)
+}
+
+/// Resolve the real client address, consuming a PROXY header if the listener expects one.
+/// Headers are only accepted from peers within the trusted CIDRs.
+pub async fn resolve_client_addr(
+    stream: &mut TcpStream,
+    peer_addr: SocketAddr,
+    mode: Option<ProxyProtocolMode>,
+    trusted: &[IpNet],
+) -> Result<SocketAddr> {
+    let Some(mode) = mode else {
+        return Ok(peer_addr);
+    };
+
+    let is_trusted = trusted.iter().any(|net| net.contains(&peer_addr.ip()));
+    if !is_trusted {
+        return match mode {
+            // Untrusted peers are treated as direct clients
+            ProxyProtocolMode::Optional => Ok(peer_addr),
+            _ => Err(anyhow!(
+                "PROXY protocol peer {} is not in proxy_protocol_trusted",
+                peer_addr.ip()
+            )),
+        };
+    }
+
+    // The first byte tells the versions apart from a PostgreSQL message or TLS record
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first).await? == 0 {
+        return Err(anyhow!("Connection closed before PROXY protocol header"));
+    }
+    let detected = match first[0] {
+        b'P' => Some(ProxyProtocolMode::V1),
+        0x0D => Some(ProxyProtocolMode::V2),
+        _ => None,
+    };
+
+    let source = match (mode, detected) {
+        (ProxyProtocolMode::V1 | ProxyProtocolMode::Optional, Some(ProxyProtocolMode::V1)) => {
+            read_v1(stream).await?
+        }
+        (ProxyProtocolMode::V2 | ProxyProtocolMode::Optional, Some(ProxyProtocolMode::V2)) => {
+            read_v2(stream).await?
+        }
+        (ProxyProtocolMode::Optional, _) => return Ok(peer_addr),
+        (expected, _) => {
+            return Err(anyhow!(
+                "Expected PROXY protocol {:?} header from {}",
+                expected,
+                peer_addr
+            ));
+        }
+    };
+
+    // LOCAL and UNKNOWN headers carry no address, e.g. load balancer health checks
+    Ok(source.unwrap_or(peer_addr))
+}
+
+/// Read a v1 header such as `PROXY TCP4 192.0.2.1 192.0.2.2 56324 6432\r\n`
+pub async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // Read byte by byte so nothing after the header is consumed
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(anyhow!("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| anyhow!("PROXY protocol v1 header is not ASCII"))?;
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(anyhow!("Invalid PROXY protocol v1 header: {}", line));
+    }
+
+    match fields.next() {
+        Some("TCP4") | Some("TCP6") => {
+            let parts: Vec<&str> = fields.collect();
+            let [source_ip, _, source_port, _] = parts[..] else {
+                return Err(anyhow!("Invalid PROXY protocol v1 header: {}", line));
+            };
+            let ip: IpAddr = source_ip
+                .parse()
+                .map_err(|_| anyhow!("Invalid source address in PROXY header: {}", source_ip))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| anyhow!("Invalid source port in PROXY header: {}", source_port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        Some("UNKNOWN") => Ok(None),
+        _ => Err(anyhow!("Invalid PROXY protocol v1 header: {}", line)),
+    }
+}
+
+/// Read a binary v2 header; TLVs are skipped
+pub async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header).await?;
+    if header[..12] != V2_SIGNATURE {
+        return Err(anyhow!("Invalid PROXY protocol v2 signature"));
+    }
+
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0F;
+    if version != 2 {
+        return Err(anyhow!("Unsupported PROXY protocol version {}", version));
+    }
+    let family = header[13];
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+
+    match command {
+        0x0 => return Ok(None), // LOCAL
+        0x1 => {}               // PROXY
+        _ => return Err(anyhow!("Unsupported PROXY protocol command {}", command)),
+    }
+
+    let too_short = || anyhow!("PROXY protocol v2 address block too short");
+    match family {
+        // TCP or UDP over IPv4: source, destination, source port, destination port
+        0x11 | 0x12 => {
+            let block = payload.get(..12).ok_or_else(too_short)?;
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP or UDP over IPv6
+        0x21 | 0x22 => {
+            let block = payload.get(..36).ok_or_else(too_short)?;
+            let octets: [u8; 16] = block[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_read_v1() {
+        let mut input: &[u8] = b"PROXY TCP4 192.0.2.10 198.51.100.1 56324 6432\r\nrest";
+        let addr = read_v1(&mut input).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.10:56324".parse().unwrap()));
+        assert_eq!(input, b"rest");
+
+        let mut input: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 6432\r\n";
+        let addr = read_v1(&mut input).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_v1(&mut input).await.unwrap(), None);
+
+        let mut input: &[u8] = b"PROXY TCP4 not-an-ip 198.51.100.1 1 2\r\n";
+        assert!(read_v1(&mut input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0F]);
+        header.extend_from_slice(&[192, 0, 2, 10, 198, 51, 100, 1]);
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&6432u16.to_be_bytes());
+        header.extend_from_slice(&[0x04, 0x00, 0x00]); // Empty NOOP TLV
+        header.extend_from_slice(b"rest");
+
+        let mut input = header.as_slice();
+        let addr = read_v2(&mut input).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.10:56324".parse().unwrap()));
+        assert_eq!(input, b"rest");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read_v2(&mut local.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_encode_v2() {
+        let source: SocketAddr = "192.0.2.10:5000".parse().unwrap();
+        let destination: SocketAddr = "198.51.100.1:6432".parse().unwrap();
+
+        let header = encode_v2(Some((source, destination)), None).unwrap();
+        assert_eq!(header.len(), 16 + 12);
+        assert_eq!(read_v2(&mut header.as_slice()).await.unwrap(), Some(source));
+
+        let mixed = encode_v2(
+            Some(("[2001:db8::1]:5000".parse().unwrap(), destination)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(mixed[13], 0x21);
+
+        let local = encode_v2(None, None).unwrap();
+        assert_eq!(&local[12..], &[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read_v2(&mut local.as_slice()).await.unwrap(), None);
+
+        let ssl = SslInfo {
+            version: "TLSv1.3".to_string(),
+            common_name: Some("app".to_string()),
+        };
+        let header = encode_v2(Some((source, destination)), Some(&ssl)).unwrap();
+        assert_eq!(read_v2(&mut header.as_slice()).await.unwrap(), Some(source));
+
+        let tlv = &header[16 + 12..];
+        let mut expected = vec![PP2_TYPE_SSL, 0x00, 21, 0x03, 0, 0, 0, 0];
+        expected.extend_from_slice(&[PP2_SUBTYPE_SSL_VERSION, 0x00, 7]);
+        expected.extend_from_slice(b"TLSv1.3");
+        expected.extend_from_slice(&[PP2_SUBTYPE_SSL_CN, 0x00, 3]);
+        expected.extend_from_slice(b"app");
+        assert_eq!(tlv, expected.as_slice());
+
+        // A certificate CN too long for a TLV is refused rather than truncated
+        let ssl = SslInfo {
+            version: "TLSv1.3".to_string(),
+            common_name: Some("a".repeat(70000)),
+        };
+        let error = encode_v2(Some((source, destination)), Some(&ssl)).unwrap_err();
+        assert!(error.to_string().contains("is too long"));
+    }
+
+    async fn connected_pair(payload: &'static [u8]) -> (TcpStream, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(payload).await.unwrap();
+            // Keep the connection open until the server side is done
+            let _ = client.read_u8().await;
+        });
+        listener.accept().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resolve_client_addr() {
+        let trusted: Vec<IpNet> = vec!["127.0.0.0/8".parse().unwrap()];
+
+        let (mut stream, peer) =
+            connected_pair(b"PROXY TCP4 192.0.2.10 127.0.0.1 5000 6432\r\n\x00").await;
+        let addr = resolve_client_addr(
+            &mut stream,
+            peer,
+            Some(ProxyProtocolMode::Optional),
+            &trusted,
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, "192.0.2.10:5000".parse().unwrap());
+        assert_eq!(stream.read_u8().await.unwrap(), 0);
+
+        // Optional mode passes through connections without a header
+        let (mut stream, peer) = connected_pair(b"\x00\x00\x00\x08").await;
+        let addr = resolve_client_addr(
+            &mut stream,
+            peer,
+            Some(ProxyProtocolMode::Optional),
+            &trusted,
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, peer);
+
+        let (mut stream, peer) = connected_pair(b"\x00\x00\x00\x08").await;
+        let result =
+            resolve_client_addr(&mut stream, peer, Some(ProxyProtocolMode::V2), &trusted).await;
+        assert!(result.is_err());
+
+        let untrusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let (mut stream, peer) =
+            connected_pair(b"PROXY TCP4 192.0.2.10 127.0.0.1 5000 6432\r\n").await;
+        let result =
+            resolve_client_addr(&mut stream, peer, Some(ProxyProtocolMode::V1), &untrusted).await;
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("is not in proxy_protocol_trusted")
+        );
+    }
+}
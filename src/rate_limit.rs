@@ -0,0 +1,325 @@
+use crate::config;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often idle buckets, old failures and expired bans are swept
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Cap on how far ahead a bucket is expected to be full, so that tiny rates cannot overflow
+const MAX_REFILL_TIME: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Rate limits on new connections and bans for clients that keep failing the TLS handshake,
+/// keyed by route bind address and client IP address
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    inner: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    routes: HashMap<String, Bucket>,
+    clients: HashMap<(String, IpAddr), Bucket>,
+    failures: HashMap<(String, IpAddr), Failures>,
+    bans: HashMap<(String, IpAddr), Instant>,
+    pruned: Option<Instant>,
+}
+
+/// Recent failed handshakes of one client, within the route's `find_time`
+struct Failures {
+    find_time: Duration,
+    times: VecDeque<Instant>,
+}
+
+/// Why a new connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// The listener's `rate_limit`
+    RouteRate,
+    /// The listener's `client_rate_limit`
+    ClientRate,
+    /// The client is banned after failed handshakes
+    Banned,
+    /// The client address is not allowed by the listener's `allow` and `deny` lists
+    Denied,
+}
+
+impl Refused {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::RouteRate => "route_rate",
+            Self::ClientRate => "client_rate",
+            Self::Banned => "banned",
+            Self::Denied => "denied",
+        }
+    }
+}
+
+/// A client banned from a route, as listed by the admin endpoint
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BanEntry {
+    pub route: String,
+    pub address: IpAddr,
+    pub remaining_seconds: u64,
+}
+
+/// Token bucket holding up to `burst` connections, refilled at `rate` per second
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again, after which it can be forgotten
+    full_at: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &config::RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst() as f64,
+            updated: now,
+            full_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &config::RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst() as f64);
+        self.updated = now;
+    }
+
+    fn take(&mut self, limit: &config::RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        let missing = limit.burst() as f64 - self.tokens;
+        let refill = Duration::try_from_secs_f64(missing / limit.rate)
+            .unwrap_or(MAX_REFILL_TIME)
+            .min(MAX_REFILL_TIME);
+        self.full_at = now + refill;
+        true
+    }
+}
+
+impl RateLimiter {
+    /// Take a token from the route's bucket for a new connection
+    pub fn check_route(&self, listener: &config::Listener) -> Result<(), Refused> {
+        self.check_route_at(listener, Instant::now())
+    }
+
+    /// Refuse banned clients, then take a token from the client's bucket
+    pub fn check_client(&self, listener: &config::Listener, client: IpAddr) -> Result<(), Refused> {
+        self.check_client_at(listener, client, Instant::now())
+    }
+
+    /// Count a failed TLS handshake, banning the client once it has failed too often.
+    /// Returns whether this failure caused a ban.
+    pub fn record_failure(&self, listener: &config::Listener, client: IpAddr) -> bool {
+        self.record_failure_at(listener, client, Instant::now())
+    }
+
+    pub fn bans(&self) -> Vec<BanEntry> {
+        let now = Instant::now();
+        let state = self.inner.lock().unwrap();
+        let mut bans = state
+            .bans
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|((route, address), until)| BanEntry {
+                route: route.clone(),
+                address: *address,
+                remaining_seconds: until.duration_since(now).as_secs(),
+            })
+            .collect::<Vec<_>>();
+        bans.sort_by(|a, b| (&a.route, a.address).cmp(&(&b.route, b.address)));
+        bans
+    }
+
+    /// Lift bans on `address`, or on every client when `None`, returning how many were lifted
+    pub fn clear_bans(&self, address: Option<IpAddr>) -> usize {
+        let mut state = self.inner.lock().unwrap();
+        let before = state.bans.len();
+        state
+            .bans
+            .retain(|(_, banned), _| address.is_some_and(|address| address != *banned));
+        state
+            .failures
+            .retain(|(_, failed), _| address.is_some_and(|address| address != *failed));
+        before - state.bans.len()
+    }
+
+    fn check_route_at(&self, listener: &config::Listener, now: Instant) -> Result<(), Refused> {
+        let Some(limit) = &listener.rate_limit else {
+            return Ok(());
+        };
+        let mut state = self.inner.lock().unwrap();
+        let bucket = state
+            .routes
+            .entry(listener.bind_address.clone())
+            .or_insert_with(|| Bucket::new(limit, now));
+        if bucket.take(limit, now) {
+            Ok(())
+        } else {
+            Err(Refused::RouteRate)
+        }
+    }
+
+    fn check_client_at(
+        &self,
+        listener: &config::Listener,
+        client: IpAddr,
+        now: Instant,
+    ) -> Result<(), Refused> {
+        let mut state = self.inner.lock().unwrap();
+        state.prune(now);
+        let key = (listener.bind_address.clone(), client);
+        if state.bans.get(&key).is_some_and(|until| *until > now) {
+            return Err(Refused::Banned);
+        }
+        let Some(limit) = &listener.client_rate_limit else {
+            return Ok(());
+        };
+        let bucket = state
+            .clients
+            .entry(key)
+            .or_insert_with(|| Bucket::new(limit, now));
+        if bucket.take(limit, now) {
+            Ok(())
+        } else {
+            Err(Refused::ClientRate)
+        }
+    }
+
+    fn record_failure_at(&self, listener: &config::Listener, client: IpAddr, now: Instant) -> bool {
+        let Some(ban) = &listener.ban else {
+            return false;
+        };
+        let mut state = self.inner.lock().unwrap();
+        let key = (listener.bind_address.clone(), client);
+        let failures = state
+            .failures
+            .entry(key.clone())
+            .or_insert_with(|| Failures {
+                find_time: ban.find_time,
+                times: VecDeque::new(),
+            });
+        failures.find_time = ban.find_time;
+        failures.times.push_back(now);
+        while failures
+            .times
+            .front()
+            .is_some_and(|failed| now.saturating_duration_since(*failed) > ban.find_time)
+        {
+            failures.times.pop_front();
+        }
+        if failures.times.len() < ban.max_failures as usize {
+            return false;
+        }
+        state.failures.remove(&key);
+        state.bans.insert(key, now + ban.ban_time);
+        true
+    }
+}
+
+impl State {
+    /// Forget state that no longer affects any decision, so that clients that come and go do
+    /// not grow the maps without bound
+    fn prune(&mut self, now: Instant) {
+        if self
+            .pruned
+            .is_some_and(|pruned| now.saturating_duration_since(pruned) < PRUNE_INTERVAL)
+        {
+            return;
+        }
+        self.pruned = Some(now);
+        self.bans.retain(|_, until| *until > now);
+        // A full bucket is the same as a new one
+        self.clients.retain(|_, bucket| bucket.full_at > now);
+        self.failures.retain(|_, failures| {
+            failures
+                .times
+                .back()
+                .is_some_and(|failed| now.saturating_duration_since(*failed) <= failures.find_time)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(settings: &str) -> config::Listener {
+        config::Listener::for_tests("127.0.0.1:6432", settings)
+    }
+
+    #[test]
+    fn test_token_buckets_limit_new_connections() {
+        let listener = listener(
+            "[rate_limit]\nrate = 10\nburst = 2\n[client_rate_limit]\nrate = 1\nburst = 1",
+        );
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert_eq!(limiter.check_client_at(&listener, first, now), Ok(()));
+        assert_eq!(
+            limiter.check_client_at(&listener, first, now),
+            Err(Refused::ClientRate)
+        );
+        assert_eq!(limiter.check_client_at(&listener, second, now), Ok(()));
+        // The client bucket refills at one token per second
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check_client_at(&listener, first, later), Ok(()));
+
+        assert_eq!(limiter.check_route_at(&listener, now), Ok(()));
+        assert_eq!(limiter.check_route_at(&listener, now), Ok(()));
+        assert_eq!(
+            limiter.check_route_at(&listener, now),
+            Err(Refused::RouteRate)
+        );
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.check_route_at(&listener, later), Ok(()));
+    }
+
+    #[test]
+    fn test_repeated_failures_ban_client() {
+        let listener =
+            listener("[ban]\nmax_failures = 3\nfind_time = \"1min\"\nban_time = \"10min\"");
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(!limiter.record_failure_at(&listener, client, now));
+        // Failures outside the find window are forgotten
+        let now = now + Duration::from_secs(120);
+        assert!(!limiter.record_failure_at(&listener, client, now));
+        assert!(!limiter.record_failure_at(&listener, client, now));
+        assert_eq!(limiter.check_client_at(&listener, client, now), Ok(()));
+        assert!(limiter.record_failure_at(&listener, client, now));
+        assert_eq!(
+            limiter.check_client_at(&listener, client, now),
+            Err(Refused::Banned)
+        );
+
+        assert_eq!(limiter.bans().len(), 1);
+        assert_eq!(limiter.bans()[0].address, client);
+        assert_eq!(limiter.clear_bans(Some("192.0.2.9".parse().unwrap())), 0);
+
+        // Bans expire after ban_time
+        let expired = now + Duration::from_secs(601);
+        assert_eq!(limiter.check_client_at(&listener, client, expired), Ok(()));
+
+        for _ in 0..3 {
+            limiter.record_failure_at(&listener, client, expired);
+        }
+        assert_eq!(
+            limiter.check_client_at(&listener, client, expired),
+            Err(Refused::Banned)
+        );
+        assert_eq!(limiter.clear_bans(None), 1);
+        assert_eq!(limiter.check_client_at(&listener, client, expired), Ok(()));
+    }
+}
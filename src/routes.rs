@@ -0,0 +1,460 @@
+use crate::config::{self, RouteFailurePolicy};
+use crate::connections::Connections;
+use crate::proxy;
+use crate::rate_limit::RateLimiter;
+use crate::upgrade::ListenerSockets;
+use anyhow::Result;
+use futures::future::{self, FutureExt};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+/// Delay before the first restart of a failed route, doubled on each further failure
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay between restarts; a route that ran this long starts over at the initial delay
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteState {
+    Starting,
+    Running,
+    /// Failed and waiting to be restarted
+    Restarting,
+    /// Failed with `route_failure = "exit"`
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteHealth {
+    pub name: Option<String>,
+    pub bind_address: String,
+    pub state: RouteState,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+/// State of every configured route, keyed by bind address
+#[derive(Clone)]
+pub struct Health(watch::Sender<BTreeMap<String, RouteHealth>>);
+
+impl Default for Health {
+    fn default() -> Self {
+        Self(watch::Sender::new(BTreeMap::new()))
+    }
+}
+
+impl Health {
+    pub fn routes(&self) -> Vec<RouteHealth> {
+        self.0.borrow().values().cloned().collect()
+    }
+
+    /// Whether there are routes and all of them are accepting connections
+    pub fn is_ready(&self) -> bool {
+        let routes = self.0.borrow();
+        !routes.is_empty()
+            && routes
+                .values()
+                .all(|route| route.state == RouteState::Running)
+    }
+
+    /// Wait until every route has either started listening or failed
+    pub async fn settled(&self) {
+        let mut routes = self.0.subscribe();
+        let _ = routes
+            .wait_for(|routes| {
+                routes
+                    .values()
+                    .all(|route| route.state != RouteState::Starting || route.restarts > 0)
+            })
+            .await;
+    }
+
+    pub fn set_running(&self, bind_address: &str) {
+        self.update(bind_address, |route| route.state = RouteState::Running);
+    }
+
+    fn insert(&self, proxy_config: &config::Proxy) {
+        let bind_address = proxy_config.listener.bind_address.clone();
+        let route = RouteHealth {
+            name: proxy_config.name.clone(),
+            bind_address: bind_address.clone(),
+            state: RouteState::Starting,
+            restarts: 0,
+            last_error: None,
+        };
+        self.0.send_modify(|routes| {
+            routes.insert(bind_address, route);
+        });
+    }
+
+    fn remove(&self, bind_address: &str) {
+        self.0.send_modify(|routes| {
+            routes.remove(bind_address);
+        });
+    }
+
+    fn update(&self, bind_address: &str, f: impl FnOnce(&mut RouteHealth)) {
+        self.0
+            .send_if_modified(|routes| match routes.get_mut(bind_address) {
+                Some(route) => {
+                    f(route);
+                    true
+                }
+                None => false,
+            });
+    }
+}
+
+/// A supervised route task, with the channels used to update and stop it
+struct RunningRoute {
+    config: config::Proxy,
+    updates: watch::Sender<config::Proxy>,
+    shutdown: CancellationToken,
+    task: JoinHandle<Result<()>>,
+}
+
+/// What a route task needs besides its configuration
+#[derive(Clone)]
+struct RouteContext {
+    connections: Connections,
+    sockets: ListenerSockets,
+    health: Health,
+    rate_limiter: RateLimiter,
+    exit_on_failure: Arc<AtomicBool>,
+}
+
+impl RunningRoute {
+    fn spawn(proxy_config: config::Proxy, context: RouteContext) -> Self {
+        let (updates, receiver) = watch::channel(proxy_config.clone());
+        let shutdown = CancellationToken::new();
+        context.health.insert(&proxy_config);
+        let task = tokio::spawn(supervise(receiver, shutdown.clone(), context));
+        Self {
+            config: proxy_config,
+            updates,
+            shutdown,
+            task,
+        }
+    }
+}
+
+/// Run a route, restarting it with backoff when it fails unless failures are fatal
+async fn supervise(
+    mut updates: watch::Receiver<config::Proxy>,
+    shutdown: CancellationToken,
+    context: RouteContext,
+) -> Result<()> {
+    let mut delay = INITIAL_RESTART_DELAY;
+    loop {
+        let proxy_config = updates.borrow_and_update().clone();
+        let bind_address = proxy_config.listener.bind_address.clone();
+        let started = Instant::now();
+        let result = proxy::run_proxy(
+            proxy_config,
+            updates.clone(),
+            shutdown.clone(),
+            context.connections.clone(),
+            context.sockets.clone(),
+            context.health.clone(),
+            context.rate_limiter.clone(),
+        )
+        .await;
+        let Err(e) = result else {
+            return Ok(());
+        };
+
+        let error = format!("{e:#}");
+        if context.exit_on_failure.load(Ordering::Relaxed) {
+            context.health.update(&bind_address, |route| {
+                route.state = RouteState::Failed;
+                route.last_error = Some(error);
+            });
+            return Err(e);
+        }
+
+        if started.elapsed() >= MAX_RESTART_DELAY {
+            delay = INITIAL_RESTART_DELAY;
+        }
+        tracing::error!(
+            "Route {} failed: {}; restarting in {:?}",
+            bind_address,
+            error,
+            delay
+        );
+        context.health.update(&bind_address, |route| {
+            route.state = RouteState::Restarting;
+            route.restarts += 1;
+            route.last_error = Some(error);
+        });
+
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(delay) => {}
+            // Updated settings may fix the failure, so try them right away
+            changed = updates.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Running routes, keyed by listener bind address
+pub struct RouteSet {
+    routes: HashMap<String, RunningRoute>,
+    context: RouteContext,
+}
+
+impl RouteSet {
+    pub fn start(
+        proxies: Vec<config::Proxy>,
+        route_failure: RouteFailurePolicy,
+        connections: Connections,
+        sockets: ListenerSockets,
+    ) -> Self {
+        let mut routes = Self {
+            routes: HashMap::new(),
+            context: RouteContext {
+                connections,
+                sockets,
+                health: Health::default(),
+                rate_limiter: RateLimiter::default(),
+                exit_on_failure: Arc::new(AtomicBool::new(false)),
+            },
+        };
+        routes.apply(proxies, route_failure);
+        routes
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn health(&self) -> Health {
+        self.context.health.clone()
+    }
+
+    pub fn rate_limiter(&self) -> RateLimiter {
+        self.context.rate_limiter.clone()
+    }
+
+    /// Bring the running routes in line with `proxies`: stop routes whose listener is gone,
+    /// start new ones, and pass changed settings to the routes that remain
+    pub fn apply(&mut self, proxies: Vec<config::Proxy>, route_failure: RouteFailurePolicy) {
+        self.context
+            .exit_on_failure
+            .store(route_failure == RouteFailurePolicy::Exit, Ordering::Relaxed);
+
+        let health = &self.context.health;
+        self.routes.retain(|bind_address, route| {
+            let keep = proxies
+                .iter()
+                .any(|proxy| &proxy.listener.bind_address == bind_address);
+            if !keep {
+                tracing::info!("Stopping route {}", bind_address);
+                route.shutdown.cancel();
+                health.remove(bind_address);
+            }
+            keep
+        });
+
+        for proxy_config in proxies {
+            let bind_address = proxy_config.listener.bind_address.clone();
+            match self.routes.get_mut(&bind_address) {
+                Some(route) if route.config == proxy_config => {}
+                Some(route) => {
+                    tracing::info!("Updating route {}", bind_address);
+                    if route.config.name != proxy_config.name {
+                        self.context.health.update(&bind_address, |health| {
+                            health.name = proxy_config.name.clone();
+                        });
+                    }
+                    route.config = proxy_config.clone();
+                    route.updates.send_replace(proxy_config);
+                }
+                None => {
+                    tracing::info!("Starting route {}", bind_address);
+                    let route = RunningRoute::spawn(proxy_config, self.context.clone());
+                    self.routes.insert(bind_address, route);
+                }
+            }
+        }
+    }
+
+    /// Stop accepting connections on every route, leaving open connections running
+    pub async fn stop(&mut self) {
+        for (bind_address, route) in &self.routes {
+            route.shutdown.cancel();
+            self.context.health.remove(bind_address);
+        }
+        for (_, route) in self.routes.drain() {
+            let _ = route.task.await;
+        }
+    }
+
+    /// Wait for a route task to exit, which only happens when its failure is fatal. Returns
+    /// its bind address and outcome.
+    pub async fn wait_any(&mut self) -> (String, Result<Result<()>, JoinError>) {
+        if self.routes.is_empty() {
+            return future::pending().await;
+        }
+        let tasks = self.routes.iter_mut().map(|(bind_address, route)| {
+            (&mut route.task).map(move |result| (bind_address.clone(), result))
+        });
+        let ((bind_address, result), _, _) = future::select_all(tasks).await;
+        self.routes.remove(&bind_address);
+        (bind_address, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn proxy(bind_address: &str, backend: &str) -> config::Proxy {
+        toml::from_str(&format!(
+            "[listener]\nbind_address = \"unix:{bind_address}\"\n[backend]\naddress = \"{backend}\""
+        ))
+        .unwrap()
+    }
+
+    async fn wait_for_socket(path: &std::path::Path, present: bool) {
+        for _ in 0..100 {
+            if path.exists() == present {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Timed out waiting for {}", path.display());
+    }
+
+    #[tokio::test]
+    async fn test_apply_diffs_routes() {
+        let directory = tempfile::tempdir().unwrap();
+        let first = directory.path().join(".s.PGSQL.6001");
+        let second = directory.path().join(".s.PGSQL.6002");
+        let first_address = first.display().to_string();
+        let second_address = second.display().to_string();
+
+        let mut routes = RouteSet::start(
+            vec![proxy(&first_address, "127.0.0.1:5432")],
+            RouteFailurePolicy::Restart,
+            Connections::default(),
+            ListenerSockets::default(),
+        );
+        wait_for_socket(&first, true).await;
+        let task_id = routes.routes[&format!("unix:{first_address}")].task.id();
+
+        // A changed backend updates the running route in place
+        routes.apply(
+            vec![
+                proxy(&first_address, "127.0.0.1:5433"),
+                proxy(&second_address, "127.0.0.1:5432"),
+            ],
+            RouteFailurePolicy::Restart,
+        );
+        assert_eq!(routes.len(), 2);
+        assert_eq!(
+            routes.routes[&format!("unix:{first_address}")].task.id(),
+            task_id
+        );
+        wait_for_socket(&second, true).await;
+
+        // A removed route stops listening
+        routes.apply(
+            vec![proxy(&second_address, "127.0.0.1:5432")],
+            RouteFailurePolicy::Restart,
+        );
+        assert_eq!(routes.len(), 1);
+        wait_for_socket(&first, false).await;
+
+        routes.stop().await;
+        assert_eq!(routes.len(), 0);
+        assert!(!second.exists());
+    }
+
+    #[tokio::test]
+    async fn test_failed_route_restarts_or_exits() {
+        // Binding fails while the port is taken
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let bind_address = taken.local_addr().unwrap().to_string();
+        let failing: config::Proxy = toml::from_str(&format!(
+            "[listener]\nbind_address = \"{bind_address}\"\n[backend]\naddress = \"127.0.0.1:5432\""
+        ))
+        .unwrap();
+
+        let mut routes = RouteSet::start(
+            vec![failing.clone()],
+            RouteFailurePolicy::Restart,
+            Connections::default(),
+            ListenerSockets::default(),
+        );
+        let health = routes.health();
+        tokio::time::timeout(Duration::from_secs(5), health.settled())
+            .await
+            .unwrap();
+        let route = &health.routes()[0];
+        assert_eq!(route.state, RouteState::Restarting);
+        assert_eq!(route.restarts, 1);
+        assert!(route.last_error.is_some());
+        assert!(!health.is_ready());
+        // The failed route keeps running under supervision
+        let waited = tokio::time::timeout(Duration::from_millis(50), routes.wait_any());
+        assert!(waited.await.is_err());
+        routes.stop().await;
+        assert!(health.routes().is_empty());
+
+        let mut routes = RouteSet::start(
+            vec![failing],
+            RouteFailurePolicy::Exit,
+            Connections::default(),
+            ListenerSockets::default(),
+        );
+        let (address, result) = routes.wait_any().await;
+        assert_eq!(address, bind_address);
+        assert!(result.unwrap().is_err());
+        assert_eq!(routes.health().routes()[0].state, RouteState::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_stop_while_route_builds() {
+        let directory = tempfile::tempdir().unwrap();
+        let socket_path = directory.path().join(".s.PGSQL.6001");
+        // A certificate server that accepts connections and never answers
+        let stalled_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_address = stalled_server.local_addr().unwrap();
+        let mut stalled = proxy(&socket_path.display().to_string(), "127.0.0.1:5432");
+        stalled.listener.server_cert = Some(
+            format!("http://{stalled_address}/server.pem")
+                .as_str()
+                .into(),
+        );
+        stalled.listener.server_key = Some(
+            format!("http://{stalled_address}/server.key")
+                .as_str()
+                .into(),
+        );
+
+        let mut routes = RouteSet::start(
+            vec![stalled],
+            RouteFailurePolicy::Restart,
+            Connections::default(),
+            ListenerSockets::default(),
+        );
+        let _stalled = stalled_server.accept().await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), routes.stop())
+            .await
+            .unwrap();
+        assert!(!socket_path.exists());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::config::StartupParameters;
+use crate::protocol::StartupMessage;
+use anyhow::{Result, anyhow};
+use std::net::SocketAddr;
+
+/// Placeholders available in startup parameter templates
+const PLACEHOLDERS: &[&str] = &[
+    "client_ip",
+    "client_port",
+    "tls_version",
+    "client_cert_cn",
+    "client_cert_subject",
+    "application_name",
+];
+
+/// Details of a client session that templates can refer to
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Client TCP address; `None` for clients on a Unix socket
+    pub client_addr: Option<SocketAddr>,
+    pub tls_version: Option<String>,
+    pub cert_common_name: Option<String>,
+    pub cert_subject: Option<String>,
+}
+
+enum Segment<'a> {
+    Literal(&'a str),
+    Placeholder(&'a str),
+}
+
+/// Split a template into literal text and `{placeholder}` references; `{{` and `}}` are escapes
+fn parse_template(template: &str) -> Result<Vec<Segment<'_>>> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while let Some(index) = rest.find(['{', '}']) {
+        segments.push(Segment::Literal(&rest[..index]));
+        let tail = &rest[index..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            segments.push(Segment::Literal(&tail[..1]));
+            rest = &tail[2..];
+        } else if tail.starts_with('}') {
+            return Err(anyhow!("Unmatched '}}' in template '{}'", template));
+        } else {
+            let end = tail
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated placeholder in template '{}'", template))?;
+            let name = &tail[1..end];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(anyhow!(
+                    "Unknown placeholder '{{{}}}' in template '{}', expected one of: {}",
+                    name,
+                    template,
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+            segments.push(Segment::Placeholder(name));
+            rest = &tail[end + 1..];
+        }
+    }
+    segments.push(Segment::Literal(rest));
+    Ok(segments)
+}
+
+pub fn validate_template(template: &str) -> Result<()> {
+    parse_template(template).map(|_| ())
+}
+
+fn render(template: &str, session: &SessionInfo, message: &StartupMessage) -> Result<String> {
+    let mut rendered = String::new();
+    for segment in parse_template(template)? {
+        match segment {
+            Segment::Literal(text) => rendered.push_str(text),
+            Segment::Placeholder(name) => {
+                let value = match name {
+                    "client_ip" => session.client_addr.map(|addr| addr.ip().to_string()),
+                    "client_port" => session.client_addr.map(|addr| addr.port().to_string()),
+                    "tls_version" => session.tls_version.clone(),
+                    "client_cert_cn" => session.cert_common_name.clone(),
+                    "client_cert_subject" => session.cert_subject.clone(),
+                    "application_name" => message.get("application_name").map(str::to_string),
+                    _ => None,
+                };
+                rendered.push_str(value.as_deref().unwrap_or(""));
+            }
+        }
+    }
+    Ok(rendered)
+}
+
+/// Escape a value for the `options` parameter, which the server splits on unescaped whitespace
+fn escape_option_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || c.is_whitespace() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Apply the configured templates to a client's StartupMessage
+pub fn rewrite(
+    parameters: &StartupParameters,
+    message: &mut StartupMessage,
+    session: &SessionInfo,
+) -> Result<()> {
+    // The server applies top-level parameters after `options`, so a client could otherwise
+    // override an injected setting by sending it as a parameter of its own
+    message.parameters.retain(|(name, _)| {
+        !parameters
+            .options
+            .keys()
+            .any(|option| option.eq_ignore_ascii_case(name))
+    });
+
+    if let Some(template) = &parameters.application_name {
+        let application_name = render(template, session, message)?;
+        message.set("application_name", application_name);
+    }
+
+    if !parameters.options.is_empty() {
+        // Appended after the client's own options, which the server applies in order
+        let mut options = message.get("options").unwrap_or_default().to_string();
+        let trailing_backslashes = options.len() - options.trim_end_matches('\\').len();
+        if trailing_backslashes % 2 == 1 {
+            // It would escape the space in front of the first appended option
+            return Err(anyhow!("Client options end in an unescaped backslash"));
+        }
+        for (name, template) in &parameters.options {
+            let value = render(template, session, message)?;
+            if !options.is_empty() {
+                options.push(' ');
+            }
+            options.push_str(&format!("-c {}={}", name, escape_option_value(&value)));
+        }
+        message.set("options", options);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn session() -> SessionInfo {
+        SessionInfo {
+            client_addr: Some("192.0.2.10:5000".parse().unwrap()),
+            tls_version: Some("TLSv1.3".to_string()),
+            cert_common_name: Some("app-user".to_string()),
+            cert_subject: Some("O=Example Corp, CN=app-user".to_string()),
+        }
+    }
+
+    fn message(parameters: &[(&str, &str)]) -> StartupMessage {
+        StartupMessage {
+            protocol_version: 196608,
+            parameters: parameters
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_validate_template() {
+        assert!(validate_template("{application_name} via {client_ip}").is_ok());
+        assert!(validate_template("{{literal}}").is_ok());
+        assert!(validate_template("{unknown}").is_err());
+        assert!(validate_template("{client_ip").is_err());
+        assert!(validate_template("client_ip}").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_application_name() {
+        let parameters = StartupParameters {
+            application_name: Some("[{client_ip}] {application_name}".to_string()),
+            options: BTreeMap::new(),
+        };
+        let mut startup = message(&[("user", "alice"), ("application_name", "psql")]);
+        rewrite(&parameters, &mut startup, &session()).unwrap();
+        assert_eq!(startup.get("application_name"), Some("[192.0.2.10] psql"));
+
+        // Without a client-supplied name the placeholder renders empty
+        let mut startup = message(&[("user", "alice")]);
+        rewrite(&parameters, &mut startup, &session()).unwrap();
+        assert_eq!(startup.get("application_name"), Some("[192.0.2.10] "));
+    }
+
+    #[test]
+    fn test_rewrite_options() {
+        let parameters = StartupParameters {
+            application_name: None,
+            options: BTreeMap::from([
+                ("pgtls.client_ip".to_string(), "{client_ip}".to_string()),
+                (
+                    "pgtls.client_subject".to_string(),
+                    "{client_cert_subject}".to_string(),
+                ),
+            ]),
+        };
+        let mut startup = message(&[("user", "alice"), ("options", "-c search_path=app")]);
+        rewrite(&parameters, &mut startup, &session()).unwrap();
+        assert_eq!(
+            startup.get("options"),
+            Some(
+                "-c search_path=app -c pgtls.client_ip=192.0.2.10 \
+                 -c pgtls.client_subject=O=Example\\ Corp,\\ CN=app-user"
+            )
+        );
+        assert_eq!(startup.get("application_name"), None);
+
+        let mut startup = message(&[("user", "alice"), ("options", "-c search_path=app\\")]);
+        assert!(rewrite(&parameters, &mut startup, &session()).is_err());
+        let mut startup = message(&[("user", "alice"), ("options", "-c search_path=app\\\\")]);
+        assert!(rewrite(&parameters, &mut startup, &session()).is_ok());
+    }
+
+    #[test]
+    fn test_rewrite_drops_spoofed_parameters() {
+        let parameters = StartupParameters {
+            application_name: None,
+            options: BTreeMap::from([("pgtls.client_ip".to_string(), "{client_ip}".to_string())]),
+        };
+        let mut startup = message(&[
+            ("user", "alice"),
+            ("pgtls.client_ip", "203.0.113.1"),
+            ("PGTLS.Client_IP", "203.0.113.2"),
+        ]);
+        rewrite(&parameters, &mut startup, &session()).unwrap();
+        assert_eq!(startup.get("pgtls.client_ip"), None);
+        assert_eq!(
+            startup.parameters,
+            vec![
+                ("user".to_string(), "alice".to_string()),
+                (
+                    "options".to_string(),
+                    "-c pgtls.client_ip=192.0.2.10".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+#[cfg(unix)]
+use {
+    crate::backend::BackendAddress,
+    crate::config,
+    crate::upgrade::ListenerSockets,
+    anyhow::Result,
+    nix::sys::socket::{SockaddrStorage, getsockname},
+    sd_notify::NotifyState,
+    std::net::SocketAddr,
+    std::os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    std::path::PathBuf,
+};
+
+/// Take listening sockets passed by systemd socket activation. Each is matched to a route by
+/// its `FileDescriptorName=`, or otherwise by the address it is bound to.
+#[cfg(unix)]
+pub fn inherit_sockets(proxies: &[config::Proxy], sockets: &ListenerSockets) -> Result<()> {
+    for (fd, name) in sd_notify::listen_fds_with_names(false)? {
+        // SAFETY: descriptors passed by systemd belong to this process and nothing else uses them
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let bound = bound_address(&socket);
+        let route = proxies
+            .iter()
+            .find(|proxy| proxy.name.as_deref() == Some(name.as_str()))
+            .or_else(|| {
+                proxies.iter().find(|proxy| {
+                    bound
+                        .as_ref()
+                        .is_some_and(|bound| bound.matches(&proxy.listener.bind_address))
+                })
+            });
+        match route {
+            Some(proxy) => {
+                tracing::info!(
+                    "Using socket {} from systemd for listener {}",
+                    name,
+                    proxy.listener.bind_address
+                );
+                sockets.add_inherited(&proxy.listener.bind_address, socket, true);
+            }
+            None => tracing::warn!("Closing socket {} from systemd that matches no route", name),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+enum BoundAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[cfg(unix)]
+impl BoundAddress {
+    fn matches(&self, bind_address: &str) -> bool {
+        match (self, BackendAddress::parse(bind_address)) {
+            (Self::Tcp(bound), Ok(BackendAddress::Tcp(address))) => {
+                address.parse::<SocketAddr>().ok() == Some(*bound)
+            }
+            (Self::Unix(bound), Ok(BackendAddress::Unix(path))) => *bound == path,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bound_address(socket: &OwnedFd) -> Option<BoundAddress> {
+    let address = getsockname::<SockaddrStorage>(socket.as_raw_fd()).ok()?;
+    if let Some(address) = address.as_sockaddr_in() {
+        return Some(BoundAddress::Tcp(SocketAddr::V4((*address).into())));
+    }
+    if let Some(address) = address.as_sockaddr_in6() {
+        return Some(BoundAddress::Tcp(SocketAddr::V6((*address).into())));
+    }
+    address
+        .as_unix_addr()
+        .and_then(|address| address.path())
+        .map(|path| BoundAddress::Unix(path.to_path_buf()))
+}
+
+#[cfg(unix)]
+fn notify(states: &[NotifyState]) {
+    // A no-op unless started by systemd with NOTIFY_SOCKET set
+    if let Err(e) = sd_notify::notify(false, states) {
+        tracing::warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Report that every route is listening. After an upgrade, this process also becomes the
+/// service's main process.
+pub fn ready(upgraded: bool) {
+    #[cfg(unix)]
+    if upgraded {
+        notify(&[NotifyState::MainPid(std::process::id()), NotifyState::Ready]);
+    } else {
+        notify(&[NotifyState::Ready]);
+    }
+    #[cfg(not(unix))]
+    let _ = upgraded;
+}
+
+/// Report that the configuration is being reloaded; followed by `ready` once it is applied
+pub fn reloading() {
+    #[cfg(unix)]
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(_) => notify(&[NotifyState::Reloading]),
+    }
+}
+
+pub fn stopping() {
+    #[cfg(unix)]
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Sends `WATCHDOG=1` at half the interval systemd expects, when `WatchdogSec=` is set
+pub struct Watchdog {
+    interval: Option<tokio::time::Interval>,
+}
+
+impl Watchdog {
+    /// After an upgrade, `WATCHDOG_PID` still names the previous process, so the interval is
+    /// taken from `WATCHDOG_USEC` alone
+    pub fn new(upgraded: bool) -> Self {
+        let interval = watchdog_usec(upgraded)
+            .filter(|usec| *usec > 0)
+            .map(|usec| tokio::time::interval(Duration::from_micros(usec) / 2));
+        Self { interval }
+    }
+
+    /// Resolve when the next keep-alive is due; never resolves when the watchdog is disabled
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+                #[cfg(unix)]
+                notify(&[NotifyState::Watchdog]);
+            }
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn watchdog_usec(upgraded: bool) -> Option<u64> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        return Some(usec);
+    }
+    if upgraded {
+        return std::env::var("WATCHDOG_USEC").ok()?.parse().ok();
+    }
+    None
+}
+
+#[cfg(not(unix))]
+fn watchdog_usec(_upgraded: bool) -> Option<u64> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::fd::AsFd;
+
+    #[test]
+    fn test_bound_address_matches_bind_address() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        let bound = bound_address(&tcp.as_fd().try_clone_to_owned().unwrap()).unwrap();
+        assert!(bound.matches(&format!("127.0.0.1:{port}")));
+        assert!(!bound.matches(&format!("0.0.0.0:{port}")));
+        assert!(!bound.matches("unix:/tmp/.s.PGSQL.5432"));
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(".s.PGSQL.6432");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let bound = bound_address(&unix.as_fd().try_clone_to_owned().unwrap()).unwrap();
+        assert!(bound.matches(&format!("unix:{}", path.display())));
+        assert!(!bound.matches(&format!("127.0.0.1:{port}")));
+    }
+}
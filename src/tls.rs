@@ -0,0 +1,608 @@
+use crate::config::{Config, CryptoProviderKind, Listener};
+use anyhow::{Context, Result, anyhow};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::hkdf::{HKDF_SHA256, Salt};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::crypto::{CryptoProvider, GetRandomFailed};
+use rustls::server::{
+    NoServerSessionStorage, ProducesTickets, ServerSessionMemoryCache, StoresServerSessions,
+};
+use rustls::{
+    ConfigBuilder, KeyLog, KeyLogFile, NamedGroup, ServerConfig, SupportedProtocolVersion,
+    TicketRotator, WantsVerifier,
+};
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Minimum length of a shared `ticket_key_file` secret
+pub const MIN_TICKET_SECRET_LEN: usize = 32;
+const TICKET_KEY_SALT: &[u8] = b"pgtls session ticket key";
+
+/// Crypto provider selected by `crypto_provider`, checked against the `fips` requirement
+pub fn select_crypto_provider(config: &Config) -> Result<CryptoProvider> {
+    let provider = match config.crypto_provider {
+        CryptoProviderKind::Ring => rustls::crypto::ring::default_provider(),
+        #[cfg(feature = "aws-lc-rs")]
+        CryptoProviderKind::AwsLcRs => rustls::crypto::aws_lc_rs::default_provider(),
+        #[cfg(not(feature = "aws-lc-rs"))]
+        CryptoProviderKind::AwsLcRs => {
+            return Err(anyhow!(
+                "crypto_provider = \"aws-lc-rs\" requires pgtls to be built with the aws-lc-rs feature"
+            ));
+        }
+    };
+
+    if config.fips && !provider.fips() {
+        return Err(anyhow!(
+            "fips = true, but the {} crypto provider is not FIPS-validated; \
+             build with --features fips and set crypto_provider = \"aws-lc-rs\"",
+            config.crypto_provider
+        ));
+    }
+    Ok(provider)
+}
+
+/// Install the configured provider as the process default and report it
+pub fn install_crypto_provider(config: &Config) -> Result<()> {
+    let provider = select_crypto_provider(config)?;
+    let fips = provider.fips();
+    provider
+        .install_default()
+        .map_err(|_| anyhow!("A crypto provider is already installed"))?;
+
+    tracing::info!(
+        "Using {} crypto provider (FIPS validated: {})",
+        config.crypto_provider,
+        fips
+    );
+    Ok(())
+}
+
+/// The installed process default provider, or ring when none was installed
+pub fn default_provider() -> CryptoProvider {
+    CryptoProvider::get_default()
+        .map(|provider| provider.as_ref().clone())
+        .unwrap_or_else(rustls::crypto::ring::default_provider)
+}
+
+/// Parse a protocol version name such as `1.2` or `TLSv1.3`
+fn protocol_version(name: &str) -> Result<&'static SupportedProtocolVersion> {
+    let version = name
+        .strip_prefix("TLSv")
+        .or_else(|| name.strip_prefix("tlsv"))
+        .unwrap_or(name);
+    match version {
+        "1.2" => Ok(&rustls::version::TLS12),
+        "1.3" => Ok(&rustls::version::TLS13),
+        _ => Err(anyhow!(
+            "Unsupported TLS version '{}', expected 1.2 or 1.3",
+            name
+        )),
+    }
+}
+
+/// Protocol versions enabled by the listener's `min_tls_version` and `max_tls_version`
+pub fn protocol_versions(listener: &Listener) -> Result<Vec<&'static SupportedProtocolVersion>> {
+    let min = listener
+        .min_tls_version
+        .as_deref()
+        .map(protocol_version)
+        .transpose()?;
+    let max = listener
+        .max_tls_version
+        .as_deref()
+        .map(protocol_version)
+        .transpose()?;
+
+    let rank = |version: &SupportedProtocolVersion| u16::from(version.version);
+    if let (Some(min), Some(max)) = (min, max)
+        && rank(min) > rank(max)
+    {
+        return Err(anyhow!(
+            "min_tls_version {} is newer than max_tls_version {}",
+            listener.min_tls_version.as_deref().unwrap_or_default(),
+            listener.max_tls_version.as_deref().unwrap_or_default()
+        ));
+    }
+
+    Ok(rustls::ALL_VERSIONS
+        .iter()
+        .copied()
+        .filter(|version| min.is_none_or(|min| rank(version) >= rank(min)))
+        .filter(|version| max.is_none_or(|max| rank(version) <= rank(max)))
+        .collect())
+}
+
+/// Keep only the entries named in `names`, in the configured order; empty keeps all
+fn select<T: Copy>(
+    available: &[T],
+    names: &[String],
+    name_of: impl Fn(&T) -> String,
+    kind: &str,
+) -> Result<Vec<T>> {
+    if names.is_empty() {
+        return Ok(available.to_vec());
+    }
+
+    names
+        .iter()
+        .map(|name| {
+            available
+                .iter()
+                .find(|item| name_of(item).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| {
+                    let known: Vec<String> = available.iter().map(&name_of).collect();
+                    anyhow!(
+                        "Unknown {} '{}', expected one of: {}",
+                        kind,
+                        name,
+                        known.join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Hybrid post-quantum key exchange group enabled by `post_quantum`
+const POST_QUANTUM_KX_GROUP: NamedGroup = NamedGroup::X25519MLKEM768;
+
+fn is_post_quantum(group: NamedGroup) -> bool {
+    format!("{group:?}").contains("MLKEM")
+}
+
+/// Crypto provider restricted to the listener's `cipher_suites` and `kx_groups`
+pub fn crypto_provider(listener: &Listener, base: CryptoProvider) -> Result<CryptoProvider> {
+    let cipher_suites = select(
+        &base.cipher_suites,
+        &listener.cipher_suites,
+        |suite| format!("{:?}", suite.suite()),
+        "cipher suite",
+    )?;
+    let mut kx_groups = select(
+        &base.kx_groups,
+        &listener.kx_groups,
+        |group| format!("{:?}", group.name()),
+        "key exchange group",
+    )?;
+
+    if listener.post_quantum {
+        // Preferred first, so clients sending a key share for it avoid a HelloRetryRequest
+        let hybrid = base
+            .kx_groups
+            .iter()
+            .find(|group| group.name() == POST_QUANTUM_KX_GROUP)
+            .copied()
+            .ok_or_else(|| {
+                anyhow!(
+                    "post_quantum requires a crypto provider supporting {:?}; set crypto_provider = \"aws-lc-rs\"",
+                    POST_QUANTUM_KX_GROUP
+                )
+            })?;
+        kx_groups.retain(|group| group.name() != POST_QUANTUM_KX_GROUP);
+        kx_groups.insert(0, hybrid);
+    } else if listener.kx_groups.is_empty() {
+        // Post-quantum groups are opt-in
+        kx_groups.retain(|group| !is_post_quantum(group.name()));
+    }
+
+    Ok(CryptoProvider {
+        cipher_suites,
+        kx_groups,
+        ..base
+    })
+}
+
+/// Server config builder honouring the listener's TLS settings, along with its provider
+pub fn server_config_builder(
+    listener: &Listener,
+    base: CryptoProvider,
+) -> Result<(
+    Arc<CryptoProvider>,
+    ConfigBuilder<ServerConfig, WantsVerifier>,
+)> {
+    let provider = Arc::new(crypto_provider(listener, base)?);
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&protocol_versions(listener)?)
+        .map_err(|e| anyhow!("Inconsistent TLS settings: {}", e))?;
+    Ok((provider, builder))
+}
+
+/// Writes TLS secrets in NSS key log format, for decrypting captures in Wireshark
+#[derive(Debug)]
+struct FileKeyLog {
+    file: Mutex<File>,
+}
+
+impl FileKeyLog {
+    fn open(path: &str) -> Result<Self> {
+        let mut options = std::fs::OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let file = options
+            .open(path)
+            .with_context(|| format!("Failed to open key log file {path}"))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl KeyLog for FileKeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{b:02x}")).collect() };
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::warn!("Failed to write TLS key log: {}", e);
+        }
+    }
+}
+
+/// Key log writer for `key_log_file`, or `SSLKEYLOGFILE` if set; `None` when neither is
+pub fn key_log(key_log_file: Option<&str>, context: &str) -> Result<Option<Arc<dyn KeyLog>>> {
+    let (key_log, path): (Arc<dyn KeyLog>, String) = match key_log_file {
+        Some(path) => (Arc::new(FileKeyLog::open(path)?), path.to_string()),
+        None => match std::env::var("SSLKEYLOGFILE") {
+            Ok(path) if !path.is_empty() => (Arc::new(KeyLogFile::new()), path),
+            _ => return Ok(None),
+        },
+    };
+
+    tracing::warn!(
+        "!!! TLS KEY LOGGING ENABLED for {} !!! Session secrets are written to {}; \
+         anyone with this file can decrypt captured traffic. Never enable this in production.",
+        context,
+        path
+    );
+    Ok(Some(key_log))
+}
+
+/// Server-side session cache for stateful resumption; a size of zero disables it
+pub fn session_storage(size: usize) -> Arc<dyn StoresServerSessions> {
+    if size == 0 {
+        Arc::new(NoServerSessionStorage {})
+    } else {
+        ServerSessionMemoryCache::new(size)
+    }
+}
+
+/// Session ticket encrypter for the listener's `session_tickets`.
+///
+/// Without `ticket_key_file`, each interval gets a fresh random key that is dropped once its
+/// tickets expire. With it, keys are derived from the shared secret instead.
+pub fn ticketer(listener: &Listener) -> Result<Arc<dyn ProducesTickets>> {
+    let Some(path) = &listener.ticket_key_file else {
+        let lifetime =
+            u32::try_from(listener.ticket_rotation_interval.as_secs()).unwrap_or(u32::MAX);
+        let rotator = TicketRotator::new(lifetime, RandomTicketKey::generate)
+            .map_err(|e| anyhow!("Failed to generate ticket key: {e}"))?;
+        return Ok(Arc::new(rotator));
+    };
+
+    let secret =
+        std::fs::read(path).with_context(|| format!("Failed to read ticket key file {path}"))?;
+    if secret.len() < MIN_TICKET_SECRET_LEN {
+        return Err(anyhow!(
+            "Ticket key file {} must hold at least {} bytes",
+            path,
+            MIN_TICKET_SECRET_LEN
+        ));
+    }
+    Ok(Arc::new(RotatingTicketer::new(
+        secret,
+        listener.ticket_rotation_interval,
+    )))
+}
+
+/// Encrypt `plain` as `prefix || nonce || ciphertext and tag`, authenticating the prefix
+fn seal_ticket(
+    key: &LessSafeKey,
+    rng: &SystemRandom,
+    prefix: &[u8],
+    plain: &[u8],
+) -> Option<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce).ok()?;
+
+    let mut ticket = Vec::with_capacity(prefix.len() + NONCE_LEN + plain.len() + 16);
+    ticket.extend_from_slice(prefix);
+    ticket.extend_from_slice(&nonce);
+    let mut in_out = plain.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(prefix),
+        &mut in_out,
+    )
+    .ok()?;
+    ticket.extend_from_slice(&in_out);
+    Some(ticket)
+}
+
+/// Decrypt the `nonce || ciphertext and tag` following a ticket's prefix
+fn open_ticket(key: &LessSafeKey, prefix: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let mut in_out = ciphertext.to_vec();
+    let plain = key
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).ok()?,
+            Aad::from(prefix),
+            &mut in_out,
+        )
+        .ok()?;
+    Some(plain.to_vec())
+}
+
+/// Ticket encrypter with a single random key; `TicketRotator` replaces it every interval
+#[derive(Debug)]
+struct RandomTicketKey {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl RandomTicketKey {
+    fn generate() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+        let rng = SystemRandom::new();
+        let mut key_bytes = [0u8; 32];
+        rng.fill(&mut key_bytes).map_err(|_| GetRandomFailed)?;
+        let key = UnboundKey::new(&AES_256_GCM, &key_bytes).map_err(|_| GetRandomFailed)?;
+        Ok(Box::new(Self {
+            key: LessSafeKey::new(key),
+            rng,
+        }))
+    }
+}
+
+impl ProducesTickets for RandomTicketKey {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        // Set by the enclosing TicketRotator
+        0
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        seal_ticket(&self.key, &self.rng, &[], plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        open_ticket(&self.key, &[], cipher)
+    }
+}
+
+/// Session ticket encrypter for a shared `ticket_key_file`, whose key changes every rotation
+/// interval.
+///
+/// Keys are derived from the secret and the current interval number, so replicas sharing the
+/// secret issue and accept each other's tickets without coordination. Tickets from the
+/// previous interval are still accepted. As the secret never changes, anyone who obtains it
+/// can decrypt tickets from every interval, past and future: this mode gives up forward
+/// secrecy for resumed sessions.
+#[derive(Debug)]
+pub struct RotatingTicketer {
+    secret: Vec<u8>,
+    interval: Duration,
+    rng: SystemRandom,
+}
+
+impl RotatingTicketer {
+    pub fn new(secret: Vec<u8>, interval: Duration) -> Self {
+        Self {
+            secret,
+            interval,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    fn current_epoch(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_secs() / self.interval.as_secs().max(1)
+    }
+
+    fn key(&self, epoch: u64) -> LessSafeKey {
+        let epoch_bytes = epoch.to_be_bytes();
+        let info = [epoch_bytes.as_slice()];
+        let mut key_bytes = [0u8; 32];
+        Salt::new(HKDF_SHA256, TICKET_KEY_SALT)
+            .extract(&self.secret)
+            .expand(&info, HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut key_bytes))
+            .expect("HKDF output length is valid");
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key_bytes).expect("key length is valid"))
+    }
+
+    /// Ticket layout: epoch (8 bytes) || nonce || ciphertext and tag
+    fn seal(&self, epoch: u64, plain: &[u8]) -> Option<Vec<u8>> {
+        seal_ticket(&self.key(epoch), &self.rng, &epoch.to_be_bytes(), plain)
+    }
+
+    fn open(&self, current_epoch: u64, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < 8 {
+            return None;
+        }
+        let (epoch_bytes, sealed) = ticket.split_at(8);
+        let epoch = u64::from_be_bytes(epoch_bytes.try_into().ok()?);
+        if epoch != current_epoch && epoch.checked_add(1) != Some(current_epoch) {
+            return None;
+        }
+        open_ticket(&self.key(epoch), epoch_bytes, sealed)
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        u32::try_from(self.interval.as_secs()).unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.seal(self.current_epoch(), plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.open(self.current_epoch(), cipher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn listener() -> Listener {
+        Listener::for_tests("127.0.0.1:0", "")
+    }
+
+    #[test]
+    fn test_protocol_versions() {
+        let mut listener = listener();
+        assert_eq!(protocol_versions(&listener).unwrap().len(), 2);
+
+        listener.min_tls_version = Some("TLSv1.3".to_string());
+        let versions = protocol_versions(&listener).unwrap();
+        assert_eq!(versions, vec![&rustls::version::TLS13]);
+
+        listener.max_tls_version = Some("1.2".to_string());
+        assert!(protocol_versions(&listener).is_err());
+
+        listener.min_tls_version = Some("1.1".to_string());
+        assert!(
+            protocol_versions(&listener)
+                .unwrap_err()
+                .to_string()
+                .contains("Unsupported TLS version '1.1'")
+        );
+    }
+
+    #[test]
+    fn test_restricted_crypto_provider() {
+        let mut listener = listener();
+        listener.cipher_suites = vec!["tls13_aes_256_gcm_sha384".to_string()];
+        listener.kx_groups = vec!["X25519".to_string()];
+
+        let provider = crypto_provider(&listener, default_provider()).unwrap();
+        assert_eq!(provider.cipher_suites.len(), 1);
+        assert_eq!(
+            format!("{:?}", provider.cipher_suites[0].suite()),
+            "TLS13_AES_256_GCM_SHA384"
+        );
+        assert_eq!(provider.kx_groups.len(), 1);
+
+        listener.kx_groups = vec!["X448".to_string()];
+        assert!(
+            crypto_provider(&listener, default_provider())
+                .unwrap_err()
+                .to_string()
+                .contains("Unknown key exchange group 'X448'")
+        );
+    }
+
+    #[test]
+    fn test_post_quantum_requires_support() {
+        let mut listener = listener();
+        listener.post_quantum = true;
+
+        let result = crypto_provider(&listener, rustls::crypto::ring::default_provider());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("post_quantum requires a crypto provider supporting X25519MLKEM768")
+        );
+    }
+
+    #[cfg(feature = "aws-lc-rs")]
+    #[test]
+    fn test_post_quantum_group_preferred() {
+        let base = rustls::crypto::aws_lc_rs::default_provider;
+        let mut listener = listener();
+
+        let provider = crypto_provider(&listener, base()).unwrap();
+        assert!(
+            provider
+                .kx_groups
+                .iter()
+                .all(|group| !is_post_quantum(group.name()))
+        );
+
+        listener.post_quantum = true;
+        let provider = crypto_provider(&listener, base()).unwrap();
+        assert_eq!(provider.kx_groups[0].name(), NamedGroup::X25519MLKEM768);
+        assert!(provider.kx_groups.len() > 1);
+    }
+
+    #[test]
+    fn test_ticketer_rotation() {
+        let interval = Duration::from_secs(3600);
+        let ticketer = RotatingTicketer::new(vec![7u8; 32], interval);
+        let replica = RotatingTicketer::new(vec![7u8; 32], interval);
+        let other = RotatingTicketer::new(vec![8u8; 32], interval);
+
+        let ticket = ticketer.seal(100, b"session state").unwrap();
+        assert_eq!(replica.open(100, &ticket).unwrap(), b"session state");
+        // Still accepted during the following interval, but not after
+        assert_eq!(replica.open(101, &ticket).unwrap(), b"session state");
+        assert!(replica.open(102, &ticket).is_none());
+        assert!(other.open(100, &ticket).is_none());
+
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ticketer.open(100, &tampered).is_none());
+
+        let roundtrip = ticketer.encrypt(b"state").unwrap();
+        assert_eq!(ticketer.decrypt(&roundtrip).unwrap(), b"state");
+    }
+
+    #[test]
+    fn test_ticketer_without_key_file_uses_random_keys() {
+        let listener = Listener::for_tests("127.0.0.1:6432", "session_tickets = true");
+        let ticketer = super::ticketer(&listener).unwrap();
+        let other = super::ticketer(&listener).unwrap();
+
+        let ticket = ticketer.encrypt(b"session state").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session state");
+        assert!(other.decrypt(&ticket).is_none());
+        // Tickets stay valid through the following interval
+        assert_eq!(ticketer.lifetime(), 2 * 6 * 60 * 60);
+    }
+
+    #[test]
+    fn test_file_key_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.log");
+        let key_log = key_log(Some(path.to_str().unwrap()), "test listener")
+            .unwrap()
+            .unwrap();
+
+        key_log.log("CLIENT_TRAFFIC_SECRET_0", &[0xab, 0x01], &[0xff]);
+        key_log.log("SERVER_TRAFFIC_SECRET_0", &[0xab, 0x01], &[0x00]);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "CLIENT_TRAFFIC_SECRET_0 ab01 ff\nSERVER_TRAFFIC_SECRET_0 ab01 00\n"
+        );
+    }
+
+    #[test]
+    fn test_suites_must_match_versions() {
+        let mut listener = listener();
+        listener.min_tls_version = Some("1.3".to_string());
+        listener.cipher_suites = vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()];
+
+        assert!(server_config_builder(&listener, default_provider()).is_err());
+    }
+}
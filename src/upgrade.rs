@@ -0,0 +1,410 @@
+#[cfg(unix)]
+use {
+    anyhow::{Context, Result, anyhow},
+    nix::fcntl::{F_SETFD, FdFlag, fcntl},
+    nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg},
+    std::collections::{HashMap, HashSet},
+    std::env,
+    std::io::{IoSlice, IoSliceMut},
+    std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+    std::path::PathBuf,
+    std::sync::{Arc, Mutex},
+    std::time::Duration,
+    tokio::io::{AsyncReadExt, AsyncWriteExt, Interest},
+    tokio::net::{UnixListener, UnixStream},
+    tokio::process::Command,
+};
+
+/// Tells a newly started process where to collect its listener sockets
+#[cfg(unix)]
+const UPGRADE_SOCKET_ENV: &str = "PGTLS_UPGRADE_SOCKET";
+/// How long the new process gets to start accepting before the upgrade is abandoned
+#[cfg(unix)]
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most listeners that can be handed over in one upgrade
+#[cfg(unix)]
+const MAX_HANDOFF_LISTENERS: usize = 128;
+/// Sent by the new process once all of its routes are listening
+#[cfg(unix)]
+const READY: u8 = b'R';
+
+/// Bound listener sockets, by bind address, that can be passed to a new pgtls process
+#[derive(Clone, Default)]
+pub struct ListenerSockets {
+    #[cfg(unix)]
+    inner: Arc<Mutex<Sockets>>,
+}
+
+#[cfg(unix)]
+#[derive(Default)]
+struct Sockets {
+    /// Received from the previous process and not yet claimed by a route
+    inherited: HashMap<String, OwnedFd>,
+    /// Duplicates of the sockets that routes are accepting on
+    active: HashMap<String, OwnedFd>,
+    /// Listeners created by the service manager, which also owns their socket files
+    managed: HashSet<String>,
+    handed_off: bool,
+}
+
+#[cfg(unix)]
+impl ListenerSockets {
+    /// Offer an already bound socket to the route listening on `bind_address`
+    pub fn add_inherited(&self, bind_address: &str, socket: OwnedFd, managed: bool) {
+        let mut sockets = self.inner.lock().unwrap();
+        sockets.inherited.insert(bind_address.to_string(), socket);
+        if managed {
+            sockets.managed.insert(bind_address.to_string());
+        }
+    }
+
+    /// Claim a socket inherited from the previous process or service manager for `bind_address`
+    pub fn take_inherited(&self, bind_address: &str) -> Option<OwnedFd> {
+        self.inner.lock().unwrap().inherited.remove(bind_address)
+    }
+
+    /// Record a route's listening socket so that it can be handed over
+    pub fn register(&self, bind_address: &str, socket: impl AsFd) -> Result<()> {
+        let socket = socket.as_fd().try_clone_to_owned()?;
+        self.inner
+            .lock()
+            .unwrap()
+            .active
+            .insert(bind_address.to_string(), socket);
+        Ok(())
+    }
+
+    pub fn unregister(&self, bind_address: &str) {
+        self.inner.lock().unwrap().active.remove(bind_address);
+    }
+
+    /// Whether the listeners now belong to a new process, which keeps using their socket files
+    pub fn handed_off(&self) -> bool {
+        self.inner.lock().unwrap().handed_off
+    }
+
+    /// Whether the socket file for `bind_address` is left for the service manager to remove
+    pub fn is_managed(&self, bind_address: &str) -> bool {
+        self.inner.lock().unwrap().managed.contains(bind_address)
+    }
+}
+
+/// Connection to the previous process during an upgrade
+#[cfg(unix)]
+pub struct Handoff {
+    stream: UnixStream,
+}
+
+#[cfg(unix)]
+impl Handoff {
+    /// Tell the previous process to stop accepting, once every route is listening, and close
+    /// inherited sockets that no route claimed
+    pub async fn complete(mut self, sockets: &ListenerSockets) {
+        let unused = std::mem::take(&mut sockets.inner.lock().unwrap().inherited);
+        for bind_address in unused.keys() {
+            tracing::info!(
+                "Closing inherited listener {} that is no longer configured",
+                bind_address
+            );
+        }
+        match self.stream.write_all(&[READY]).await {
+            Ok(()) => tracing::info!("Took over listeners from the previous process"),
+            Err(e) => tracing::warn!("Failed to notify the previous process: {}", e),
+        }
+    }
+}
+
+/// Collect listener sockets from the previous process when started by an upgrade
+#[cfg(unix)]
+pub async fn inherit(sockets: &ListenerSockets) -> Result<Option<Handoff>> {
+    let Some(path) = env::var_os(UPGRADE_SOCKET_ENV) else {
+        return Ok(None);
+    };
+    let mut stream = UnixStream::connect(&path).await.with_context(|| {
+        format!(
+            "Failed to connect to upgrade socket {}",
+            path.to_string_lossy()
+        )
+    })?;
+
+    let mut payload = vec![0u8; 64 * 1024];
+    let (mut length, fds) = stream
+        .async_io(Interest::READABLE, || {
+            receive_fds(stream.as_raw_fd(), &mut payload).map_err(std::io::Error::other)
+        })
+        .await?;
+    // Anything not covered by the first read follows as plain data
+    while !payload[..length].ends_with(b"\n\n") {
+        if length == payload.len() {
+            return Err(anyhow!("Upgrade handoff message is too long"));
+        }
+        let n = stream.read(&mut payload[length..]).await?;
+        if n == 0 {
+            return Err(anyhow!("Previous process closed the upgrade socket"));
+        }
+        length += n;
+    }
+
+    let addresses = parse_handoff(std::str::from_utf8(&payload[..length - 2])?)?;
+    if addresses.len() != fds.len() {
+        return Err(anyhow!(
+            "Received {} listener sockets for {} addresses",
+            fds.len(),
+            addresses.len()
+        ));
+    }
+    tracing::info!(
+        "Inherited {} listener(s) from the previous process",
+        fds.len()
+    );
+    for ((address, managed), fd) in addresses.into_iter().zip(fds) {
+        sockets.add_inherited(&address, fd, managed);
+    }
+    Ok(Some(Handoff { stream }))
+}
+
+/// Bind addresses and whether the service manager owns each socket, from the handoff message:
+/// one line per socket with a `0` or `1` flag and the address. Empty when the previous process
+/// had no listeners, for example while every route was waiting to restart.
+#[cfg(unix)]
+fn parse_handoff(message: &str) -> Result<Vec<(String, bool)>> {
+    if message.is_empty() {
+        return Ok(Vec::new());
+    }
+    message
+        .split('\n')
+        .map(|line| match line.split_once(' ') {
+            Some((managed, address)) => Ok((address.to_string(), managed == "1")),
+            None => Err(anyhow!("Invalid upgrade handoff message")),
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn receive_fds(socket: RawFd, payload: &mut [u8]) -> Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = [IoSliceMut::new(payload)];
+    let mut space = nix::cmsg_space!([RawFd; MAX_HANDOFF_LISTENERS]);
+    let message = recvmsg::<()>(socket, &mut iov, Some(&mut space), MsgFlags::empty())?;
+
+    let mut fds = Vec::new();
+    for cmsg in message.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            // SAFETY: SCM_RIGHTS hands this process new descriptors that nothing else owns
+            fds.extend(
+                received
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    if message.flags.contains(MsgFlags::MSG_CTRUNC) {
+        return Err(anyhow!("Listener sockets were truncated during handoff"));
+    }
+    for fd in &fds {
+        // Not passed on to processes started by a later upgrade, which get their own copies
+        fcntl(fd.as_raw_fd(), F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    Ok((message.bytes, fds))
+}
+
+/// Start a new pgtls process with the same arguments and pass it the listener sockets.
+/// Returns once the new process is accepting, after which this one should drain and exit.
+#[cfg(unix)]
+pub async fn hand_off(sockets: &ListenerSockets) -> Result<()> {
+    // A directory only this user can enter, so the socket path cannot be taken over in advance
+    let directory = tempfile::Builder::new()
+        .prefix("pgtls-upgrade-")
+        .tempdir()
+        .context("Failed to create upgrade socket directory")?;
+    let path = directory.path().join("upgrade.sock");
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind upgrade socket {}", path.display()))?;
+    let program = upgrade_program()?;
+    let mut child = Command::new(&program)
+        .args(env::args_os().skip(1))
+        .env(UPGRADE_SOCKET_ENV, &path)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", program.display()))?;
+    tracing::info!(
+        "Started {} (pid {}) to take over listeners",
+        program.display(),
+        child.id().unwrap_or_default()
+    );
+
+    let child_pid = child.id().map(|pid| pid as i32);
+    let exchange = async {
+        let (stream, _) = listener.accept().await?;
+        // Anyone able to reach the socket path could otherwise take the listeners
+        let peer_pid = stream.peer_cred()?.pid();
+        if peer_pid.is_none() || peer_pid != child_pid {
+            return Err(anyhow!(
+                "Upgrade socket was connected to by an unexpected process"
+            ));
+        }
+        send_sockets(stream, sockets).await
+    };
+    let result = tokio::select! {
+        result = tokio::time::timeout(UPGRADE_TIMEOUT, exchange) => {
+            result.unwrap_or_else(|_| Err(anyhow!("New process did not start accepting within {:?}", UPGRADE_TIMEOUT)))
+        }
+        status = child.wait() => Err(anyhow!("New process exited during upgrade: {}", status?)),
+    };
+    drop(listener);
+    drop(directory);
+    if result.is_err() {
+        let _ = child.start_kill();
+    }
+    result
+}
+
+/// The executable to start, which after an upgrade on disk is a new file at the same path
+#[cfg(unix)]
+fn upgrade_program() -> Result<PathBuf> {
+    let program = env::current_exe()?;
+    // Linux reports a replaced executable as "<path> (deleted)"
+    if !program.exists()
+        && let Some(path) = program
+            .to_str()
+            .and_then(|path| path.strip_suffix(" (deleted)"))
+    {
+        return Ok(PathBuf::from(path));
+    }
+    Ok(program)
+}
+
+#[cfg(unix)]
+async fn send_sockets(mut stream: UnixStream, sockets: &ListenerSockets) -> Result<()> {
+    let (lines, fds): (Vec<String>, Vec<OwnedFd>) = {
+        let inner = sockets.inner.lock().unwrap();
+        inner
+            .active
+            .iter()
+            .map(|(address, fd)| {
+                let managed = u8::from(inner.managed.contains(address));
+                Ok((format!("{managed} {address}"), fd.try_clone()?))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip()
+    };
+    if fds.len() > MAX_HANDOFF_LISTENERS {
+        return Err(anyhow!(
+            "Cannot hand over more than {} listeners",
+            MAX_HANDOFF_LISTENERS
+        ));
+    }
+
+    let payload = format!("{}\n\n", lines.join("\n"));
+    let raw_fds = fds.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+    let rights = [ControlMessage::ScmRights(&raw_fds)];
+    let control: &[ControlMessage] = if raw_fds.is_empty() { &[] } else { &rights };
+    let sent = stream
+        .async_io(Interest::WRITABLE, || {
+            sendmsg::<()>(
+                stream.as_raw_fd(),
+                &[IoSlice::new(payload.as_bytes())],
+                control,
+                MsgFlags::empty(),
+                None,
+            )
+            .map_err(std::io::Error::from)
+        })
+        .await?;
+    stream.write_all(&payload.as_bytes()[sent..]).await?;
+
+    let mut ready = [0u8; 1];
+    stream
+        .read_exact(&mut ready)
+        .await
+        .context("New process failed before accepting connections")?;
+    if ready[0] != READY {
+        return Err(anyhow!("Unexpected reply from new process during upgrade"));
+    }
+    sockets.inner.lock().unwrap().handed_off = true;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_sockets_pass_between_processes() {
+        let old = ListenerSockets::default();
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        old.register("127.0.0.1:6432", &tcp_listener).unwrap();
+
+        let (old_side, new_side) = UnixStream::pair().unwrap();
+        let sender = tokio::spawn({
+            let old = old.clone();
+            async move { send_sockets(old_side, &old).await }
+        });
+
+        // Stand in for `inherit`, which reads the socket path from the environment
+        let new = ListenerSockets::default();
+        let mut payload = vec![0u8; 1024];
+        let (length, fds) = new_side
+            .async_io(Interest::READABLE, || {
+                receive_fds(new_side.as_raw_fd(), &mut payload).map_err(std::io::Error::other)
+            })
+            .await
+            .unwrap();
+        assert_eq!(&payload[..length], b"0 127.0.0.1:6432\n\n");
+        new.add_inherited("127.0.0.1:6432", fds.into_iter().next().unwrap(), false);
+
+        // The inherited socket accepts connections made to the original listener
+        let inherited = std::net::TcpListener::from(new.take_inherited("127.0.0.1:6432").unwrap());
+        inherited.set_nonblocking(true).unwrap();
+        let inherited = TcpListener::from_std(inherited).unwrap();
+        drop(tcp_listener);
+        let _client = tokio::net::TcpStream::connect(address).await.unwrap();
+        inherited.accept().await.unwrap();
+
+        new.register("127.0.0.1:6432", &inherited).unwrap();
+        let handoff = Handoff { stream: new_side };
+        handoff.complete(&new).await;
+        sender.await.unwrap().unwrap();
+        assert!(old.handed_off());
+        assert!(!new.handed_off());
+    }
+
+    #[test]
+    fn test_parse_handoff() {
+        assert_eq!(
+            parse_handoff("0 127.0.0.1:6432\n1 unix:/run/pgtls/.s.PGSQL.5432").unwrap(),
+            vec![
+                ("127.0.0.1:6432".to_string(), false),
+                ("unix:/run/pgtls/.s.PGSQL.5432".to_string(), true),
+            ]
+        );
+        // A process without listeners, e.g. with every route waiting to restart
+        assert!(parse_handoff("").unwrap().is_empty());
+        assert!(parse_handoff("garbage").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hand_off_without_listeners() {
+        let (old_side, new_side) = UnixStream::pair().unwrap();
+        let sender =
+            tokio::spawn(async move { send_sockets(old_side, &ListenerSockets::default()).await });
+
+        let mut payload = vec![0u8; 1024];
+        let (length, fds) = new_side
+            .async_io(Interest::READABLE, || {
+                receive_fds(new_side.as_raw_fd(), &mut payload).map_err(std::io::Error::other)
+            })
+            .await
+            .unwrap();
+        assert_eq!(&payload[..length], b"\n\n");
+        assert!(fds.is_empty());
+        let message = std::str::from_utf8(&payload[..length - 2]).unwrap();
+        assert!(parse_handoff(message).unwrap().is_empty());
+
+        Handoff { stream: new_side }
+            .complete(&ListenerSockets::default())
+            .await;
+        sender.await.unwrap().unwrap();
+    }
+}
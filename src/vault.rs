@@ -0,0 +1,421 @@
+use crate::cert_manager::{CertResolver, certified_key_from_pem, http_client};
+use crate::config::{Vault, VaultAuth};
+use anyhow::{Context, Result, anyhow};
+use reqwest::StatusCode;
+use rustls_pemfile::certs;
+use serde_json::{Value, json};
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Bounds for the delay between failed issuance attempts
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+struct IssuedCertificate {
+    cert_pem: String,
+    key_pem: String,
+    expiration: SystemTime,
+}
+
+/// Client for the Vault PKI secrets engine, logging in with the configured auth method
+struct VaultClient {
+    http: reqwest::Client,
+    config: Vault,
+    token: Option<String>,
+    token_expires_at: Option<Instant>,
+}
+
+impl VaultClient {
+    fn new(config: Vault) -> Result<Self> {
+        let mut roots = Vec::new();
+        if let Some(ca_path) = &config.ca_cert {
+            let ca_content = std::fs::read(ca_path)
+                .with_context(|| format!("Failed to read Vault ca_cert {ca_path}"))?;
+            for cert in certs(&mut BufReader::new(ca_content.as_slice())) {
+                roots.push(cert?);
+            }
+        }
+
+        Ok(Self {
+            http: http_client(roots)?,
+            config,
+            token: None,
+            token_expires_at: None,
+        })
+    }
+
+    async fn post(
+        &self,
+        path: &str,
+        token: Option<&str>,
+        body: &Value,
+    ) -> Result<(StatusCode, Value)> {
+        let url = format!("{}/v1/{}", self.config.address.trim_end_matches('/'), path);
+        let mut request = self.http.post(&url).body(body.to_string());
+        if let Some(token) = token {
+            request = request.header("X-Vault-Token", token);
+        }
+        if let Some(namespace) = &self.config.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| anyhow!("Vault request to {} failed: {}", url, e))?;
+        let status = response.status();
+        let text = response.text().await?;
+        let value = if text.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(&text)
+                .map_err(|e| anyhow!("Invalid Vault response from {}: {}", url, e))?
+        };
+
+        if !status.is_success() && status != StatusCode::FORBIDDEN {
+            return Err(anyhow!(
+                "Vault request to {} failed with {}: {}",
+                url,
+                status,
+                value["errors"]
+            ));
+        }
+        Ok((status, value))
+    }
+
+    /// Return a usable token, logging in again once the current one has expired
+    async fn token(&mut self) -> Result<String> {
+        if let Some(token) = &self.token
+            && self
+                .token_expires_at
+                .is_none_or(|expires_at| Instant::now() < expires_at)
+        {
+            return Ok(token.clone());
+        }
+
+        let (login_path, payload) = match &self.config.auth {
+            VaultAuth::Token { token, token_file } => {
+                // Read on every use so tokens rotated by an agent are picked up
+                return match (token, token_file) {
+                    (Some(token), _) => Ok(token.clone()),
+                    (None, Some(path)) => read_secret_file(path),
+                    (None, None) => std::env::var("VAULT_TOKEN")
+                        .map_err(|_| anyhow!("No Vault token configured")),
+                };
+            }
+            VaultAuth::AppRole {
+                role_id,
+                secret_id,
+                secret_id_file,
+                mount,
+            } => {
+                let mut payload = json!({ "role_id": role_id });
+                match (secret_id, secret_id_file) {
+                    (Some(secret_id), _) => payload["secret_id"] = json!(secret_id),
+                    (None, Some(path)) => payload["secret_id"] = json!(read_secret_file(path)?),
+                    (None, None) => {}
+                }
+                (format!("auth/{mount}/login"), payload)
+            }
+            VaultAuth::Kubernetes {
+                role,
+                jwt_path,
+                mount,
+            } => (
+                format!("auth/{mount}/login"),
+                json!({ "role": role, "jwt": read_secret_file(jwt_path)? }),
+            ),
+        };
+
+        let (status, response) = self.post(&login_path, None, &payload).await?;
+        if status == StatusCode::FORBIDDEN {
+            return Err(anyhow!(
+                "Vault login at {} was denied: {}",
+                login_path,
+                response["errors"]
+            ));
+        }
+
+        let token = response["auth"]["client_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Vault login at {} returned no client_token", login_path))?
+            .to_string();
+        // Log in again shortly before the lease runs out; zero means the token does not expire
+        let lease = response["auth"]["lease_duration"].as_u64().unwrap_or(0);
+        self.token_expires_at =
+            (lease > 0).then(|| Instant::now() + Duration::from_secs(lease) * 9 / 10);
+        self.token = Some(token.clone());
+
+        tracing::info!("Logged in to Vault via {}", login_path);
+        Ok(token)
+    }
+
+    async fn issue(&mut self) -> Result<IssuedCertificate> {
+        let path = format!("{}/issue/{}", self.config.pki_mount, self.config.role);
+        let mut payload = json!({ "common_name": self.config.common_name });
+        if !self.config.alt_names.is_empty() {
+            payload["alt_names"] = json!(self.config.alt_names.join(","));
+        }
+        if let Some(ttl) = &self.config.ttl {
+            payload["ttl"] = json!(ttl);
+        }
+
+        let mut retried = false;
+        let response = loop {
+            let token = self.token().await?;
+            let (status, response) = self.post(&path, Some(&token), &payload).await?;
+            if status != StatusCode::FORBIDDEN {
+                break response;
+            }
+            // The token may have been revoked early; log in once more before giving up
+            if retried || matches!(self.config.auth, VaultAuth::Token { .. }) {
+                return Err(anyhow!(
+                    "Vault denied issuing from {}: {}",
+                    path,
+                    response["errors"]
+                ));
+            }
+            self.token = None;
+            retried = true;
+        };
+
+        let data = &response["data"];
+        let mut cert_pem = data["certificate"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Vault response from {} has no certificate", path))?
+            .to_string();
+        let chain: Vec<&str> = match data["ca_chain"].as_array() {
+            Some(chain) => chain.iter().filter_map(Value::as_str).collect(),
+            None => data["issuing_ca"].as_str().into_iter().collect(),
+        };
+        for ca in chain {
+            cert_pem.push('\n');
+            cert_pem.push_str(ca);
+        }
+
+        let key_pem = data["private_key"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Vault response from {} has no private_key", path))?
+            .to_string();
+        let expiration = data["expiration"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("Vault response from {} has no expiration", path))?;
+
+        Ok(IssuedCertificate {
+            cert_pem,
+            key_pem,
+            expiration: UNIX_EPOCH + Duration::from_secs(expiration),
+        })
+    }
+}
+
+/// Issue the initial certificate, then keep renewing it in the background
+pub async fn start_vault_task(
+    config: Vault,
+    resolver: Arc<CertResolver>,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let renew_fraction = config.renew_fraction;
+    let mut client = VaultClient::new(config)?;
+    let mut renew_in = issue_and_install(&mut client, &resolver, renew_fraction).await?;
+
+    Ok(tokio::spawn(async move {
+        let mut retry_interval = MIN_RETRY_INTERVAL;
+        loop {
+            tokio::time::sleep(renew_in).await;
+
+            match issue_and_install(&mut client, &resolver, renew_fraction).await {
+                Ok(delay) => {
+                    renew_in = delay;
+                    retry_interval = MIN_RETRY_INTERVAL;
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to renew Vault certificate for {}: {:#}",
+                        client.config.common_name,
+                        e
+                    );
+                    renew_in = retry_interval;
+                    retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+                }
+            }
+        }
+    }))
+}
+
+/// Issue a certificate, swap it into the resolver and return the delay until renewal
+async fn issue_and_install(
+    client: &mut VaultClient,
+    resolver: &CertResolver,
+    renew_fraction: f64,
+) -> Result<Duration> {
+    let issued = client.issue().await?;
+    resolver.set_certificate(certified_key_from_pem(&issued.cert_pem, &issued.key_pem)?);
+
+    let renew_in = renewal_delay(issued.expiration, renew_fraction);
+    tracing::info!(
+        "Installed Vault certificate for {}, renewing in {}s",
+        client.config.common_name,
+        renew_in.as_secs()
+    );
+    Ok(renew_in)
+}
+
+fn renewal_delay(expiration: SystemTime, renew_fraction: f64) -> Duration {
+    let ttl = expiration
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    ttl.mul_f64(renew_fraction).max(MIN_RETRY_INTERVAL)
+}
+
+fn read_secret_file(path: &str) -> Result<String> {
+    std::fs::read_to_string(path)
+        .map(|content| content.trim().to_string())
+        .with_context(|| format!("Failed to read {path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_http::{self, MockResponse};
+    use rcgen::{Certificate, CertificateParams};
+
+    /// Start a mock Vault that accepts the given login and issues from `pki/issue/pgtls`
+    async fn start_mock_vault(login_path: &'static str, expected_login: Value) -> String {
+        mock_http::serve(move |request| match request.path.as_str() {
+            path if path == login_path => {
+                assert_eq!(request.json(), expected_login);
+                MockResponse::json(
+                    200,
+                    json!({ "auth": { "client_token": "s.mock", "lease_duration": 3600 } }),
+                )
+            }
+            "/v1/pki/issue/pgtls" => {
+                if request.header("x-vault-token") != Some("s.mock") {
+                    return MockResponse::json(403, json!({ "errors": ["permission denied"] }));
+                }
+                let body = request.json();
+                let cert = Certificate::from_params(CertificateParams::new(vec![
+                    body["common_name"].as_str().unwrap().to_string(),
+                ]))
+                .unwrap();
+                let expiration = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+                    + 3600;
+                MockResponse::json(
+                    200,
+                    json!({
+                        "data": {
+                            "certificate": cert.serialize_pem().unwrap(),
+                            "private_key": cert.serialize_private_key_pem(),
+                            "issuing_ca": cert.serialize_pem().unwrap(),
+                            "expiration": expiration,
+                        }
+                    }),
+                )
+            }
+            _ => MockResponse::json(404, json!({ "errors": [] })),
+        })
+        .await
+    }
+
+    fn vault_config(address: String, auth: VaultAuth) -> Vault {
+        Vault {
+            address,
+            pki_mount: "pki".to_string(),
+            role: "pgtls".to_string(),
+            common_name: "db.example.com".to_string(),
+            alt_names: vec![],
+            ttl: Some("1h".to_string()),
+            renew_fraction: 0.5,
+            namespace: None,
+            ca_cert: None,
+            auth,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_issue_with_approle() {
+        let address = start_mock_vault(
+            "/v1/auth/approle/login",
+            json!({ "role_id": "db-proxy", "secret_id": "s3cret" }),
+        )
+        .await;
+        let config = vault_config(
+            address,
+            VaultAuth::AppRole {
+                role_id: "db-proxy".to_string(),
+                secret_id: Some("s3cret".to_string()),
+                secret_id_file: None,
+                mount: "approle".to_string(),
+            },
+        );
+
+        let resolver = Arc::new(CertResolver::default());
+        let handle = start_vault_task(config, resolver.clone()).await.unwrap();
+        assert!(resolver.has_certificate());
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_issue_with_kubernetes_auth() {
+        let jwt_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(jwt_file.path(), "service-account-jwt\n").unwrap();
+
+        let address = start_mock_vault(
+            "/v1/auth/kubernetes/login",
+            json!({ "role": "pgtls", "jwt": "service-account-jwt" }),
+        )
+        .await;
+        let mut client = VaultClient::new(vault_config(
+            address,
+            VaultAuth::Kubernetes {
+                role: "pgtls".to_string(),
+                jwt_path: jwt_file.path().to_string_lossy().to_string(),
+                mount: "kubernetes".to_string(),
+            },
+        ))
+        .unwrap();
+
+        let issued = client.issue().await.unwrap();
+        assert!(issued.expiration > SystemTime::now());
+        // Certificate followed by the issuing CA
+        assert_eq!(issued.cert_pem.matches("BEGIN CERTIFICATE").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_issue_with_invalid_token() {
+        let address = start_mock_vault("/v1/auth/approle/login", Value::Null).await;
+        let mut client = VaultClient::new(vault_config(
+            address,
+            VaultAuth::Token {
+                token: Some("s.revoked".to_string()),
+                token_file: None,
+            },
+        ))
+        .unwrap();
+
+        let result = client.issue().await;
+        assert!(result.is_err());
+        assert!(
+            result
+                .err()
+                .unwrap()
+                .to_string()
+                .contains("permission denied")
+        );
+    }
+
+    #[test]
+    fn test_renewal_delay() {
+        let expiration = SystemTime::now() + Duration::from_secs(3600);
+        let delay = renewal_delay(expiration, 0.5);
+        assert!(delay <= Duration::from_secs(1800) && delay > Duration::from_secs(1790));
+
+        // Already expired certificates are retried after the minimum interval
+        let expired = SystemTime::now() - Duration::from_secs(60);
+        assert_eq!(renewal_delay(expired, 0.5), MIN_RETRY_INTERVAL);
+    }
+}
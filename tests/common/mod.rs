@@ -7,6 +7,7 @@ use std::fs::File;
 use std::io::{BufReader, Write};
 use std::net::TcpListener;
 use std::path::Path;
+use std::sync::Arc;
 use tempfile::TempDir;
 use tokio::net::TcpStream;
 
@@ -118,7 +119,11 @@ pub fn create_test_client_config(
         root_store.add(cert)?;
     }
 
-    let config_builder = ClientConfig::builder().with_root_certificates(root_store);
+    // Explicit provider, as builds with the aws-lc-rs feature have two available
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config_builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store);
 
     let config = if let Some((cert_pem, key_pem)) = client_cert {
         let client_cert_der: Vec<CertificateDer> =
@@ -135,6 +140,17 @@ pub fn create_test_client_config(
     Ok(config)
 }
 
+/// Encode a protocol 3.0 StartupMessage for `user`
+pub fn startup_message(user: &str) -> Vec<u8> {
+    let mut body = 196608u32.to_be_bytes().to_vec();
+    for field in ["user", user, ""] {
+        body.extend_from_slice(field.as_bytes());
+        body.push(0);
+    }
+    let length = (body.len() + 4) as u32;
+    [length.to_be_bytes().to_vec(), body].concat()
+}
+
 /// Mock plaintext backend server that echoes data
 pub async fn run_mock_plaintext_backend(port: u16) -> Result<()> {
     let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{port}")).await?;
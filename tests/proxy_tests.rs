@@ -75,16 +75,16 @@ async fn test_tls_to_plaintext_integration() -> Result<()> {
         let server_name = ServerName::try_from("localhost")?;
         let mut tls_stream = connector.connect(server_name, stream).await?;
 
-        // Send test data
-        let test_payload = b"integration test tls-to-plaintext";
-        tls_stream.write_all(test_payload).await?;
+        // Send a StartupMessage, which the proxy waits for before connecting the backend
+        let test_payload = startup_message("tls-to-plaintext");
+        tls_stream.write_all(&test_payload).await?;
 
         // Read response with timeout
         let mut buffer = vec![0u8; test_payload.len()];
         timeout(Duration::from_secs(2), tls_stream.read_exact(&mut buffer)).await??;
 
         // Verify echo
-        assert_eq!(&buffer, test_payload, "Data was not echoed correctly");
+        assert_eq!(buffer, test_payload, "Data was not echoed correctly");
 
         // Gracefully close the TLS stream
         tls_stream.shutdown().await.ok();
@@ -171,9 +171,9 @@ async fn test_mtls_integration() -> Result<()> {
         let server_name = ServerName::try_from("localhost")?;
         let mut tls_stream = connector.connect(server_name, stream).await?;
 
-        // Send test data
-        let test_payload = b"integration test mtls with cert";
-        tls_stream.write_all(test_payload).await?;
+        // Send a StartupMessage, which the proxy waits for before connecting the backend
+        let test_payload = startup_message("mtls-with-cert");
+        tls_stream.write_all(&test_payload).await?;
 
         // Read response
         let mut buffer = vec![0u8; test_payload.len()];
@@ -181,7 +181,7 @@ async fn test_mtls_integration() -> Result<()> {
 
         // Verify echo
         assert_eq!(
-            &buffer, test_payload,
+            buffer, test_payload,
             "Data was not echoed correctly with mTLS"
         );
 
@@ -257,11 +257,28 @@ async fn test_mtls_integration() -> Result<()> {
         Ok::<_, anyhow::Error>(())
     }.await;
 
+    // Test 3: A plaintext client cannot skip the certificate check
+    let test_plaintext_result = async {
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{proxy_port}")).await?;
+        stream.write_all(&startup_message("plaintext")).await?;
+
+        let mut response = Vec::new();
+        timeout(Duration::from_secs(2), stream.read_to_end(&mut response)).await??;
+        assert_eq!(response.first(), Some(&b'E'), "Expected an ErrorResponse");
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.contains("FATAL"));
+        assert!(response.contains("28000"));
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
     // Clean up
     proxy_process.kill().ok();
     backend_task.abort();
 
     test_with_cert_result?;
     test_without_cert_result?;
+    test_plaintext_result?;
     Ok(())
 }